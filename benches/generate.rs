@@ -0,0 +1,69 @@
+//! Times map generation with a voronoi graph of about 100,000 cells. Run with
+//! `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use mapgen::{CancelToken, Grid, Map, MapOptions};
+use mapgen::rng;
+
+const RUNS: u32 = 5;
+
+fn bench<T, F: FnMut() -> T>(name: &str, mut f: F) {
+    let mut total = Duration::default();
+    let mut best = Duration::from_secs(u64::MAX);
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let result = f();
+        let elapsed = start.elapsed();
+        drop(result);
+        total += elapsed;
+        best = best.min(elapsed);
+    }
+    println!("{:<12} best {:>10.2?}  mean {:>10.2?}", name, best, total / RUNS);
+}
+
+fn main() {
+    let options = MapOptions {
+        cells: 100_000,
+        ..MapOptions::default()
+    };
+    let seed = 42;
+
+    bench("grid", || {
+        let mut rng = rng::stage_rng(seed, rng::POINTS, 0);
+        Grid::new(
+            options.size,
+            options.cells,
+            options.point_distribution,
+            options.topology,
+            &mut rng,
+            &CancelToken::new(),
+        ).unwrap()
+    });
+
+    let map = Map::generate_with_seed(options.clone(), seed).unwrap();
+    let voronoi = &map.grid.voronoi;
+    let heights = &map.grid.heights;
+    bench("neighbors", || {
+        let mut sum = 0.0;
+        for cell in voronoi.cells() {
+            for adjacent in voronoi.cell(cell).adjacent_cells {
+                sum += heights[adjacent.as_usize()] as f64;
+            }
+        }
+        sum
+    });
+
+    let grid = &map.grid;
+    bench("locate", || {
+        let mut found = 0;
+        for y in (0..grid.size.height).step_by(3) {
+            for x in (0..grid.size.width).step_by(3) {
+                found += grid.coords_to_cell_index(x as f32, y as f32).as_usize();
+            }
+        }
+        found
+    });
+
+    bench("generate", || Map::generate_with_seed(options.clone(), seed).unwrap());
+}
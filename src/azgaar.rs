@@ -0,0 +1,535 @@
+//! Reading and writing the `.map` save files of the original JavaScript
+//! generator.
+//!
+//! A `.map` file is a list of sections separated by `\r\n`: a `|`-delimited
+//! header, the settings, map coordinates, biomes, notes, the whole SVG, the
+//! grid as JSON, the grid layers as comma-separated numbers and finally the
+//! pack with its features, cultures, states, burgs and so on.
+//!
+//! Only the grid is mapped onto this crate's types. Every other section is
+//! kept verbatim, so a loaded file is written back unchanged apart from the
+//! grid. Files written from a `Map` carry empty pack sections, as the
+//! cultures, states, burgs and other layers the original generator keeps there
+//! are not generated here. Only flat maps can be written, the original
+//! generator has no maps that wrap around.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use triangulation::Point;
+
+use crate::{
+    CancelToken,
+    Coast,
+    Feature,
+    FeatureType,
+    Grid,
+    IslandGroup,
+    LakeGroup,
+    Map,
+    MapError,
+    MapOptions,
+    PointDistribution,
+    Size,
+    Topology,
+};
+use crate::heightmap;
+use crate::pack::PackGrid;
+
+/// Version written into the header of exported files.
+pub const AZGAAR_VERSION: &str = "1.0";
+
+const LICENSE: &str = "File can be loaded in azgaar.github.io/Fantasy-Map-Generator";
+
+// Number of sections up to and including the last grid layer.
+const GRID_SECTIONS: usize = 12;
+
+// Defaults of the settings section, in the order the original generator writes
+// them. The empty entries are filled in from `MapOptions`.
+const DEFAULT_SETTINGS: [&str; 20] = [
+    "mi", "3", "square", "ft", "", "°C", "2", "", "0.2", "#ffffff", "99", "99",
+    "", "", "", "", "", "", "", "",
+];
+const SETTING_HEIGHT_EXPONENT: usize = 4;
+const SETTING_POPULATION_RATE: usize = 12;
+const SETTING_URBANIZATION: usize = 13;
+const SETTING_MAP_SIZE: usize = 14;
+const SETTING_LATITUDE: usize = 15;
+const SETTING_TEMPERATURE_EQUATOR: usize = 16;
+const SETTING_TEMPERATURE_POLE: usize = 17;
+const SETTING_PREC: usize = 18;
+const SETTING_WINDS: usize = 19;
+
+// Empty pack sections, in file order, for maps that have no pack.
+const EMPTY_PACK: [&str; 21] = [
+    "[0]", "[]", "[]", "[]", "", "", "", "", "", "", "", "", "", "", "", "", "", "[]", "[]",
+    "", "[]",
+];
+
+#[derive(Debug)]
+pub enum AzgaarError {
+    /// The file ends before the named section.
+    MissingSection(&'static str),
+    InvalidValue {
+        section: &'static str,
+        value: String,
+    },
+    Json(&'static str, serde_json::Error),
+    /// A grid layer does not have one value per cell.
+    WrongLength(&'static str),
+    /// The map has more features than feature ids fit in the original
+    /// generator's 16-bit layer.
+    TooManyFeatures(usize),
+    /// The map wraps around, which the original generator does not support.
+    UnsupportedTopology(Topology),
+}
+
+impl fmt::Display for AzgaarError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AzgaarError::MissingSection(s) => write!(f, "missing {} section", s),
+            AzgaarError::InvalidValue { section, value } =>
+                write!(f, "invalid value \"{}\" in {} section", value, section),
+            AzgaarError::Json(s, e) => write!(f, "invalid {} section: {}", s, e),
+            AzgaarError::WrongLength(s) => write!(f, "{} do not match the number of cells", s),
+            AzgaarError::TooManyFeatures(n) => write!(
+                f,
+                "{} features are more than the {} the format can hold",
+                n,
+                u16::MAX,
+            ),
+            AzgaarError::UnsupportedTopology(t) =>
+                write!(f, "{} maps can't be written, only flat ones", t),
+        }
+    }
+}
+
+impl std::error::Error for AzgaarError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AzgaarError::Json(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A grid feature as stored by the original generator.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AzgaarFeature {
+    /// 1-based index, 0 is a placeholder in the original generator.
+    pub i: usize,
+    pub land: bool,
+    pub border: bool,
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GridGeneral {
+    spacing: f32,
+    cells_x: u32,
+    cells_y: u32,
+    boundary: Vec<[f32; 2]>,
+    points: Vec<[f32; 2]>,
+    features: Vec<serde_json::Value>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AzgaarGrid {
+    pub spacing: f32,
+    pub cells_x: u32,
+    pub cells_y: u32,
+    pub boundary: Vec<[f32; 2]>,
+    pub points: Vec<[f32; 2]>,
+    pub features: Vec<AzgaarFeature>,
+    pub heights: Vec<u8>,
+    pub prec: Vec<u8>,
+    /// 1-based feature index of each cell.
+    pub feature_ids: Vec<u16>,
+    /// 1 for land next to water, -1 for water next to land.
+    pub coast_distance: Vec<i8>,
+    pub temperatures: Vec<i8>,
+}
+
+/// The contents of a `.map` file.
+#[derive(Clone, Debug)]
+pub struct AzgaarMap {
+    pub version: String,
+    /// Date the file was saved, as written by the original generator.
+    pub date: String,
+    pub seed: u64,
+    pub width: u32,
+    pub height: u32,
+    /// The settings section split on `|`.
+    pub settings: Vec<String>,
+    pub coords: String,
+    pub biomes: String,
+    pub notes: String,
+    pub svg: String,
+    pub grid: AzgaarGrid,
+    /// Sections following the grid layers, verbatim.
+    pub pack: Vec<String>,
+}
+
+fn parse_list<T: std::str::FromStr>(section: &'static str, s: &str) -> Result<Vec<T>, AzgaarError> {
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+    s.split(',')
+        .map(|v| v.parse().map_err(|_| AzgaarError::InvalidValue {
+            section,
+            value: v.to_owned(),
+        }))
+        .collect()
+}
+
+fn join_list<T: ToString>(values: &[T]) -> String {
+    values.iter().map(T::to_string).collect::<Vec<_>>().join(",")
+}
+
+impl AzgaarMap {
+    pub fn parse(data: &str) -> Result<Self, AzgaarError> {
+        let sections: Vec<&str> = data.split("\r\n").collect();
+        let section = |i: usize, name: &'static str| {
+            sections.get(i).copied().ok_or(AzgaarError::MissingSection(name))
+        };
+
+        let params: Vec<&str> = section(0, "header")?.split('|').collect();
+        let param = |i: usize| params.get(i).copied().ok_or(AzgaarError::MissingSection("header"));
+        let invalid = |section: &'static str, value: &str| AzgaarError::InvalidValue {
+            section,
+            value: value.to_owned(),
+        };
+        let version = param(0)?.to_owned();
+        let date = param(2)?.to_owned();
+        let seed = param(3)?.parse().map_err(|_| invalid("header", params[3]))?;
+        let width = param(4)?.parse().map_err(|_| invalid("header", params[4]))?;
+        let height = param(5)?.parse().map_err(|_| invalid("header", params[5]))?;
+
+        let settings = section(1, "settings")?.split('|').map(str::to_owned).collect();
+
+        let general: GridGeneral = serde_json::from_str(section(6, "grid")?)
+            .map_err(|e| AzgaarError::Json("grid", e))?;
+        let features = general.features
+            .into_iter()
+            // The first entry is a `0` placeholder.
+            .skip(1)
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()
+            .map_err(|e| AzgaarError::Json("grid features", e))?;
+
+        let grid = AzgaarGrid {
+            spacing: general.spacing,
+            cells_x: general.cells_x,
+            cells_y: general.cells_y,
+            boundary: general.boundary,
+            points: general.points,
+            features,
+            heights: parse_list("heights", section(7, "heights")?)?,
+            prec: parse_list("precipitation", section(8, "precipitation")?)?,
+            feature_ids: parse_list("feature ids", section(9, "feature ids")?)?,
+            coast_distance: parse_list("coast distance", section(10, "coast distance")?)?,
+            temperatures: parse_list("temperatures", section(11, "temperatures")?)?,
+        };
+
+        Ok(AzgaarMap {
+            version,
+            date,
+            seed,
+            width,
+            height,
+            settings,
+            coords: section(2, "coordinates")?.to_owned(),
+            biomes: section(3, "biomes")?.to_owned(),
+            notes: section(4, "notes")?.to_owned(),
+            svg: section(5, "svg")?.to_owned(),
+            grid,
+            pack: sections[GRID_SECTIONS..].iter().map(|s| (*s).to_owned()).collect(),
+        })
+    }
+
+    /// Convert a map generated by this crate. Options without a counterpart
+    /// in the original generator are dropped. Maps that wrap around can't be
+    /// converted.
+    pub fn from_map(map: &Map) -> Result<Self, AzgaarError> {
+        let grid = &map.grid;
+        let options = &map.options;
+        if grid.topology != Topology::Flat {
+            return Err(AzgaarError::UnsupportedTopology(grid.topology));
+        }
+        let cells = grid.voronoi.cell_count();
+        // Feature ids are 1-based, 0 is no feature.
+        if grid.features.len() > u16::MAX as usize {
+            return Err(AzgaarError::TooManyFeatures(grid.features.len()));
+        }
+
+        let mut settings: Vec<String> = DEFAULT_SETTINGS.iter().map(|s| (*s).to_owned()).collect();
+        settings[SETTING_HEIGHT_EXPONENT] = options.height_exponent.to_string();
+        settings[SETTING_POPULATION_RATE] = options.population_rate.to_string();
+        settings[SETTING_URBANIZATION] = options.urbanization.to_string();
+        settings[SETTING_MAP_SIZE] = options.map_size.to_string();
+        settings[SETTING_LATITUDE] = options.latitude.to_string();
+        settings[SETTING_TEMPERATURE_EQUATOR] = options.temperature_equator.to_string();
+        settings[SETTING_TEMPERATURE_POLE] = options.temperature_pole.to_string();
+        settings[SETTING_PREC] = options.prec.to_string();
+        settings[SETTING_WINDS] = serde_json::to_string(&options.winds).unwrap();
+
+        let features = grid.features
+            .iter()
+            .map(|f| AzgaarFeature {
+                i: f.index + 1,
+                land: f.land,
+                border: f.border,
+                ty: match f.ty {
+                    FeatureType::Ocean => "ocean",
+                    FeatureType::Lake(_) => "lake",
+                    FeatureType::Island(_) => "island",
+                }.to_owned(),
+            })
+            .collect();
+
+        Ok(AzgaarMap {
+            version: AZGAAR_VERSION.to_owned(),
+            date: String::new(),
+            seed: map.seed,
+            width: options.size.width,
+            height: options.size.height,
+            settings,
+            coords: "{}".to_owned(),
+            biomes: "||".to_owned(),
+            notes: "[]".to_owned(),
+            svg: format!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" id=\"map\" width=\"{}\" height=\"{}\"></svg>",
+                options.size.width,
+                options.size.height,
+            ),
+            grid: AzgaarGrid {
+                spacing: grid.point_spacing,
+                cells_x: grid.cells_x,
+                cells_y: grid.cells_y,
+                boundary: grid.boundary.iter().map(|p| [p.x, p.y]).collect(),
+                points: grid.points.iter().map(|p| [p.x, p.y]).collect(),
+                features,
+                heights: grid.heights.iter().map(|&h| heightmap::whole_height(h)).collect(),
+                prec: vec![0; cells],
+                feature_ids: grid.feature_map
+                    .iter()
+                    .map(|f| f.map_or(0, |f| f as u16 + 1))
+                    .collect(),
+                coast_distance: grid.coasts
+                    .iter()
+                    .map(|c| match c {
+                        Coast::Beach => 1,
+                        Coast::Shallows => -1,
+                        Coast::None => 0,
+                    })
+                    .collect(),
+                temperatures: vec![0; cells],
+            },
+            pack: EMPTY_PACK.iter().map(|s| (*s).to_owned()).collect(),
+        })
+    }
+
+    /// Rebuild the grid of this file as a `Map`. The template is unknown, so
+    /// none is recorded.
+    pub fn to_map(&self) -> Result<Map, MapError> {
+        let size = Size::new(self.width, self.height);
+        let mut options = MapOptions {
+            size,
+            cells: self.grid.points.len() as u32,
+            ..MapOptions::default()
+        };
+        self.apply_settings(&mut options)?;
+
+        let mut grid = Grid::from_points(
+            size,
+            options.cells,
+            PointDistribution::Jittered,
+            Topology::Flat,
+            self.grid.spacing,
+            self.grid.boundary.iter().map(|&[x, y]| Point::new(x, y)).collect(),
+            self.grid.points.iter().map(|&[x, y]| Point::new(x, y)).collect(),
+        )?;
+
+        let cells = grid.voronoi.cell_count();
+        if self.grid.heights.len() != cells {
+            return Err(AzgaarError::WrongLength("heights").into());
+        }
+        grid.heights = self.grid.heights.iter().map(|&h| f32::from(h)).collect();
+
+        if self.grid.feature_ids.len() == cells && self.grid.coast_distance.len() == cells {
+            let features = self.grid.features.len();
+            if let Some(f) = self.grid.feature_ids.iter().find(|&&f| f as usize > features) {
+                return Err(AzgaarError::InvalidValue {
+                    section: "feature ids",
+                    value: f.to_string(),
+                }.into());
+            }
+            grid.feature_map = self.grid.feature_ids
+                .iter()
+                .map(|&f| if f == 0 { None } else { Some(f as usize - 1) })
+                .collect();
+            grid.coasts = self.grid.coast_distance
+                .iter()
+                .map(|&t| match t {
+                    1 => Coast::Beach,
+                    -1 => Coast::Shallows,
+                    _ => Coast::None,
+                })
+                .collect();
+            grid.features = self.grid.features
+                .iter()
+                .map(|f| Feature {
+                    index: f.i.saturating_sub(1),
+                    land: f.land,
+                    border: f.border,
+                    ty: match f.ty.as_str() {
+                        "ocean" => FeatureType::Ocean,
+                        "lake" => FeatureType::Lake(LakeGroup::Freshwater),
+                        _ => FeatureType::Island(IslandGroup::Island),
+                    },
+                })
+                .collect();
+        } else {
+            // Older files may lack the layers, recompute them instead.
+            grid.mark_features(&CancelToken::new())?;
+        }
+
+        let mut map = Map::new(options, self.seed);
+        map.pack = PackGrid::new(&grid)?;
+        map.grid = grid;
+        Ok(map)
+    }
+
+    fn apply_settings(&self, options: &mut MapOptions) -> Result<(), AzgaarError> {
+        fn set<T: std::str::FromStr>(
+            settings: &[String],
+            i: usize,
+            value: &mut T,
+        ) -> Result<(), AzgaarError> {
+            match settings.get(i) {
+                Some(s) if !s.is_empty() => {
+                    *value = s.parse().map_err(|_| AzgaarError::InvalidValue {
+                        section: "settings",
+                        value: s.clone(),
+                    })?;
+                }
+                _ => {}
+            }
+            Ok(())
+        }
+
+        let s = &self.settings;
+        set(s, SETTING_HEIGHT_EXPONENT, &mut options.height_exponent)?;
+        set(s, SETTING_POPULATION_RATE, &mut options.population_rate)?;
+        set(s, SETTING_URBANIZATION, &mut options.urbanization)?;
+        set(s, SETTING_MAP_SIZE, &mut options.map_size)?;
+        set(s, SETTING_LATITUDE, &mut options.latitude)?;
+        set(s, SETTING_TEMPERATURE_EQUATOR, &mut options.temperature_equator)?;
+        set(s, SETTING_TEMPERATURE_POLE, &mut options.temperature_pole)?;
+        set(s, SETTING_PREC, &mut options.prec)?;
+        if let Some(winds) = s.get(SETTING_WINDS).filter(|w| !w.is_empty()) {
+            options.winds = serde_json::from_str(winds)
+                .map_err(|e| AzgaarError::Json("winds", e))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for AzgaarMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let grid = &self.grid;
+        let mut features = vec![serde_json::Value::from(0)];
+        features.extend(grid.features.iter().map(|f| serde_json::to_value(f).unwrap()));
+        let general = GridGeneral {
+            spacing: grid.spacing,
+            cells_x: grid.cells_x,
+            cells_y: grid.cells_y,
+            boundary: grid.boundary.clone(),
+            points: grid.points.clone(),
+            features,
+        };
+
+        let params = [
+            self.version.clone(),
+            LICENSE.to_owned(),
+            self.date.clone(),
+            self.seed.to_string(),
+            self.width.to_string(),
+            self.height.to_string(),
+        ];
+        let sections = [
+            params.join("|"),
+            self.settings.join("|"),
+            self.coords.clone(),
+            self.biomes.clone(),
+            self.notes.clone(),
+            self.svg.clone(),
+            serde_json::to_string(&general).map_err(|_| fmt::Error)?,
+            join_list(&grid.heights),
+            join_list(&grid.prec),
+            join_list(&grid.feature_ids),
+            join_list(&grid.coast_distance),
+            join_list(&grid.temperatures),
+        ];
+
+        write!(f, "{}", sections.join("\r\n"))?;
+        for section in &self.pack {
+            write!(f, "\r\n{}", section)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate(topology: Topology) -> Map {
+        let options = MapOptions {
+            size: Size::new(200, 100),
+            cells: crate::MIN_CELLS,
+            topology,
+            latitude: 30,
+            ..MapOptions::default()
+        };
+        Map::generate_with_seed(options, 11).unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_the_file_format() {
+        let map = generate(Topology::Flat);
+        let data = AzgaarMap::from_map(&map).unwrap().to_string();
+        let loaded = AzgaarMap::parse(&data).unwrap().to_map().unwrap();
+
+        assert_eq!(loaded.seed, map.seed);
+        assert_eq!(loaded.options.size, map.options.size);
+        assert_eq!(loaded.options.latitude, 30);
+        assert_eq!(loaded.grid.points, map.grid.points);
+        let heights: Vec<_> = map.grid.heights
+            .iter()
+            .map(|&h| f32::from(heightmap::whole_height(h)))
+            .collect();
+        assert_eq!(loaded.grid.heights, heights);
+        assert_eq!(loaded.grid.feature_map, map.grid.feature_map);
+        assert_eq!(loaded.grid.coasts, map.grid.coasts);
+        assert_eq!(loaded.grid.features.len(), map.grid.features.len());
+        for (a, b) in loaded.grid.features.iter().zip(&map.grid.features) {
+            assert_eq!((a.index, a.land, a.border), (b.index, b.land, b.border));
+        }
+
+        // Nothing is lost the second time around.
+        assert_eq!(AzgaarMap::from_map(&loaded).unwrap().to_string(), data);
+    }
+
+    #[test]
+    fn rejects_maps_that_wrap_around() {
+        for &topology in &[Topology::Cylinder, Topology::Sphere] {
+            match AzgaarMap::from_map(&generate(topology)) {
+                Err(AzgaarError::UnsupportedTopology(t)) => assert_eq!(t, topology),
+                other => panic!("expected {} to be unsupported, got {:?}", topology, other.err()),
+            }
+        }
+    }
+}
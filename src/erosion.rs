@@ -0,0 +1,305 @@
+//! Hydraulic and thermal erosion of the heightmap.
+//!
+//! Every pass lets a droplet of rain run downhill from each land cell, picking
+//! up sediment on steep slopes and dropping it where the slope flattens out,
+//! which carves valleys between ridges. Then material slides down from every
+//! cell that is steeper than the talus slope towards its lower neighbors.
+//!
+//! The coastline is kept as the template left it: land is never worn below
+//! sea level and sediment washed out to sea never raises it above.
+
+use std::cmp::Ordering;
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+use crate::{CancelToken, Grid, MapError};
+use crate::heightmap::{self, OCEAN_HEIGHT, WORLD_MAX};
+use crate::voronoi::CellIndex;
+use crate::{time_end, time_start};
+
+// Share of the missing load a droplet picks up in a cell.
+const EROSION_RATE: f32 = 0.1;
+// Share of the excess load a droplet drops in a cell.
+const DEPOSITION_RATE: f32 = 0.3;
+// Share of the excess height above the talus slope that slides down per pass.
+const THERMAL_RATE: f32 = 0.5;
+// Flattest slope a droplet still carries sediment on, so it does not drop all
+// of it on the first bit of level ground.
+const MIN_SLOPE: f32 = 0.05;
+// Cells a droplet flows through at most on the reference grid.
+const MAX_DROPLET_STEPS: f32 = 60.0;
+// Water below which a droplet has evaporated.
+const MIN_WATER: f32 = 0.01;
+
+/// Parameters of the erosion stage.
+///
+/// Heights and slopes are measured on a grid of 10,000 cells, like the
+/// heightmap primitives, and scaled to the actual grid.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Erosion {
+    /// Passes of hydraulic and thermal erosion. None by default, like the
+    /// original generator.
+    pub iterations: u32,
+    /// Water each droplet starts with.
+    pub rainfall: f32,
+    /// Sediment a droplet can carry per unit of water and of height it drops
+    /// from one cell to the next.
+    pub sediment_capacity: f32,
+    /// Share of its water a droplet loses in every cell it flows through,
+    /// from 0 to 1.
+    pub evaporation: f32,
+    /// Height difference between neighboring cells above which material
+    /// slides down.
+    pub talus: f32,
+}
+
+impl Default for Erosion {
+    fn default() -> Self {
+        Erosion {
+            iterations: 0,
+            rainfall: 1.0,
+            sediment_capacity: 1.0,
+            evaporation: 0.05,
+            talus: 6.0,
+        }
+    }
+}
+
+impl Erosion {
+    /// Erode the heights of `grid`, after they are generated and before the
+    /// features are marked. Stops with `MapError::Cancelled` between passes
+    /// once `cancel` is cancelled.
+    pub fn apply(&self, grid: &mut Grid, rng: &mut StdRng, cancel: &CancelToken) -> Result<(), MapError> {
+        self.validate()?;
+        if self.iterations == 0 {
+            return Ok(());
+        }
+        time_start!("erode");
+
+        let scale = heightmap::cell_scale(grid);
+        let mut heights = std::mem::take(&mut grid.heights);
+        let mut land: Vec<_> = grid.voronoi.cells().filter(|c| heights[c.as_usize()] >= OCEAN_HEIGHT).collect();
+        for _ in 0..self.iterations {
+            if let Err(e) = cancel.check() {
+                grid.heights = heights;
+                return Err(e);
+            }
+            land.shuffle(rng);
+            for &cell in &land {
+                self.droplet(grid, &mut heights, cell, scale);
+            }
+            self.slide(grid, &mut heights, scale);
+        }
+
+        for h in &mut heights {
+            *h = h.clamp(0.0, WORLD_MAX);
+        }
+        grid.heights = heights;
+        time_end!("erode");
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), MapError> {
+        let non_negative = |x: f32| x.is_finite() && x >= 0.0;
+        if !non_negative(self.rainfall) {
+            return Err(MapError::InvalidErosion("rainfall must be a finite number of 0 or more"));
+        }
+        if !non_negative(self.sediment_capacity) {
+            return Err(MapError::InvalidErosion("sediment capacity must be a finite number of 0 or more"));
+        }
+        if !(0.0..=1.0).contains(&self.evaporation) {
+            return Err(MapError::InvalidErosion("evaporation must be from 0 to 1"));
+        }
+        if !non_negative(self.talus) {
+            return Err(MapError::InvalidErosion("talus must be a finite number of 0 or more"));
+        }
+        Ok(())
+    }
+
+    // Let a droplet run downhill from `start` until it reaches the sea, a pit
+    // or evaporates.
+    fn droplet(&self, grid: &Grid, heights: &mut [f32], start: CellIndex, scale: f32) {
+        let mut cell = start.as_usize();
+        let mut water = self.rainfall;
+        let mut sediment = 0.0;
+        // Paths cross more cells on finer grids.
+        let max_steps = (MAX_DROPLET_STEPS * scale).ceil() as usize;
+        for _ in 0..max_steps {
+            let h = heights[cell];
+            if h < OCEAN_HEIGHT {
+                break;
+            }
+            let next = grid.voronoi
+                .cell(cell.into())
+                .adjacent_cells
+                .iter()
+                .map(|c| c.as_usize())
+                .min_by(|&a, &b| heights[a].partial_cmp(&heights[b]).unwrap_or(Ordering::Equal));
+            let next = match next {
+                Some(next) if heights[next] < h => next,
+                // A pit, the sediment settles in it.
+                _ => break,
+            };
+
+            // Slopes are gentler on finer grids, measure them per reference
+            // cell.
+            let drop = h - heights[next];
+            let capacity = (drop * scale).max(MIN_SLOPE) * water * self.sediment_capacity;
+            if sediment > capacity {
+                let deposit = (sediment - capacity) * DEPOSITION_RATE;
+                heights[cell] += deposit;
+                sediment -= deposit;
+            } else {
+                let erode = ((capacity - sediment) * EROSION_RATE).min(drop).min(h - OCEAN_HEIGHT).max(0.0);
+                heights[cell] -= erode;
+                sediment += erode;
+            }
+
+            cell = next;
+            water *= 1.0 - self.evaporation;
+            if water < MIN_WATER {
+                break;
+            }
+        }
+        deposit(heights, cell, sediment);
+    }
+
+    // Move material down from every cell to its neighbors that are lower by
+    // more than the talus slope.
+    fn slide(&self, grid: &Grid, heights: &mut [f32], scale: f32) {
+        let talus = self.talus / scale;
+        let mut changes = vec![0.0; heights.len()];
+        for cell in grid.voronoi.cells() {
+            let i = cell.as_usize();
+            let h = heights[i];
+            let adjacent = grid.voronoi.cell(cell).adjacent_cells;
+            let excess = |c: &CellIndex| (h - heights[c.as_usize()] - talus).max(0.0);
+            let total: f32 = adjacent.iter().map(excess).sum();
+            if total <= 0.0 {
+                continue;
+            }
+            let steepest = adjacent.iter().map(excess).fold(0.0, f32::max);
+            let mut moved = THERMAL_RATE * steepest / 2.0;
+            if h >= OCEAN_HEIGHT {
+                moved = moved.min(h - OCEAN_HEIGHT);
+            }
+            changes[i] -= moved;
+            for c in adjacent {
+                changes[c.as_usize()] += moved * excess(c) / total;
+            }
+        }
+        for i in 0..heights.len() {
+            let h = heights[i];
+            heights[i] = if h < OCEAN_HEIGHT {
+                ocean_limit(h, h + changes[i])
+            } else {
+                h + changes[i]
+            };
+        }
+    }
+}
+
+// Drop `sediment` in `cell`. At sea only as much as keeps it below sea level,
+// the rest is washed away.
+fn deposit(heights: &mut [f32], cell: usize, sediment: f32) {
+    let h = heights[cell];
+    heights[cell] = if h < OCEAN_HEIGHT {
+        ocean_limit(h, h + sediment)
+    } else {
+        h + sediment
+    };
+}
+
+// `raised` for a cell at sea at height `h`, kept at least a whole height below
+// sea level unless it was above that already, so the coast stays clear.
+fn ocean_limit(h: f32, raised: f32) -> f32 {
+    raised.min(h.max(OCEAN_HEIGHT - 1.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{PointDistribution, Size, Topology};
+    use crate::heightmap::{HeightmapGenerator, Template};
+
+    fn generate() -> (Grid, StdRng) {
+        let mut rng = StdRng::seed_from_u64(3);
+        let cancel = CancelToken::new();
+        let mut grid = Grid::new(
+            Size::new(200, 100),
+            crate::MIN_CELLS,
+            PointDistribution::Jittered,
+            Topology::Flat,
+            &mut rng,
+            &cancel,
+        ).unwrap();
+        HeightmapGenerator::generate_with_template(&mut grid, &mut rng, Template::HighIsland, &cancel).unwrap();
+        (grid, rng)
+    }
+
+    fn erosion(iterations: u32) -> Erosion {
+        Erosion {
+            iterations,
+            ..Erosion::default()
+        }
+    }
+
+    #[test]
+    fn keeps_the_coastline() {
+        let (mut grid, mut rng) = generate();
+        let before = grid.heights.clone();
+        erosion(5).apply(&mut grid, &mut rng, &CancelToken::new()).unwrap();
+
+        assert_ne!(grid.heights, before);
+        for (&h, &old) in grid.heights.iter().zip(&before) {
+            assert!((0.0..=WORLD_MAX).contains(&h), "height {} out of range", h);
+            assert_eq!(h >= OCEAN_HEIGHT, old >= OCEAN_HEIGHT, "{} became {}", old, h);
+        }
+    }
+
+    #[test]
+    fn no_iterations_leave_the_heights() {
+        let (mut grid, mut rng) = generate();
+        let before = grid.heights.clone();
+        erosion(0).apply(&mut grid, &mut rng, &CancelToken::new()).unwrap();
+        assert_eq!(grid.heights, before);
+    }
+
+    #[test]
+    fn cancelling_leaves_the_heights() {
+        let (mut grid, mut rng) = generate();
+        let before = grid.heights.clone();
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        match erosion(5).apply(&mut grid, &mut rng, &cancel) {
+            Err(MapError::Cancelled) => {}
+            other => panic!("expected cancellation, got {:?}", other),
+        }
+        assert_eq!(grid.heights, before);
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        let invalid = [
+            Erosion { rainfall: -1.0, ..erosion(1) },
+            Erosion { rainfall: f32::INFINITY, ..erosion(1) },
+            Erosion { sediment_capacity: f32::NAN, ..erosion(1) },
+            Erosion { evaporation: 1.5, ..erosion(1) },
+            Erosion { evaporation: f32::NAN, ..erosion(1) },
+            Erosion { talus: f32::INFINITY, ..erosion(1) },
+        ];
+        for erosion in &invalid {
+            let (mut grid, mut rng) = generate();
+            match erosion.apply(&mut grid, &mut rng, &CancelToken::new()) {
+                Err(MapError::InvalidErosion(_)) => {}
+                other => panic!("expected {:?} to be invalid, got {:?}", erosion, other),
+            }
+        }
+    }
+}
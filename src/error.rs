@@ -0,0 +1,108 @@
+use std::fmt;
+use std::io;
+
+use rand::distributions::WeightedError;
+
+use crate::Size;
+use crate::azgaar::AzgaarError;
+use crate::image::ImageError;
+use crate::save::SaveError;
+
+/// Everything that can go wrong while generating, drawing or loading a map.
+#[derive(Debug)]
+pub enum MapError {
+    /// The map is empty or too small to hold the requested number of cells.
+    InvalidSize(Size),
+    /// The number of cells asked for is outside of `MIN_CELLS..=MAX_CELLS`.
+    CellCountOutOfRange(u32),
+    /// The points could not be triangulated, e.g. because they are collinear.
+    Triangulation,
+    /// A cell was not assigned to any feature.
+    UnmappedCell(usize),
+    /// A coastline or height contour could not be traced around `cell`.
+    BrokenContour {
+        cell: usize,
+    },
+    /// No heightmap template can be picked from the template weights.
+    TemplateWeights(WeightedError),
+    /// The map mask can't be applied, for the given reason.
+    InvalidMask(&'static str),
+    /// The erosion parameters are out of range, for the given reason.
+    InvalidErosion(&'static str),
+    /// No stage with this name is in the pipeline.
+    UnknownStage(String),
+    /// The requested feature is not supported yet.
+    Unsupported(&'static str),
+    /// Generation or drawing was stopped through a `CancelToken`.
+    Cancelled,
+    /// A drawn layer could not be written out.
+    Io(io::Error),
+    Save(SaveError),
+    Azgaar(AzgaarError),
+    Image(ImageError),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::InvalidSize(size) => write!(f, "invalid map size {}", size),
+            MapError::CellCountOutOfRange(cells) => write!(
+                f,
+                "{} cells is out of range, expected {} to {}",
+                cells,
+                crate::MIN_CELLS,
+                crate::MAX_CELLS,
+            ),
+            MapError::Triangulation => write!(f, "could not triangulate the grid points"),
+            MapError::UnmappedCell(i) => write!(f, "cell {} does not belong to any feature", i),
+            MapError::BrokenContour { cell } => write!(f, "could not trace the contour at cell {}", cell),
+            MapError::TemplateWeights(e) => write!(f, "invalid template weights: {}", e),
+            MapError::InvalidMask(reason) => write!(f, "invalid mask: {}", reason),
+            MapError::InvalidErosion(reason) => write!(f, "invalid erosion: {}", reason),
+            MapError::UnknownStage(name) => write!(f, "no stage named \"{}\" in pipeline", name),
+            MapError::Unsupported(what) => write!(f, "{} is not supported yet", what),
+            MapError::Cancelled => write!(f, "cancelled"),
+            MapError::Io(e) => write!(f, "{}", e),
+            MapError::Save(e) => write!(f, "{}", e),
+            MapError::Azgaar(e) => write!(f, "{}", e),
+            MapError::Image(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapError::TemplateWeights(e) => Some(e),
+            MapError::Io(e) => Some(e),
+            MapError::Save(e) => Some(e),
+            MapError::Azgaar(e) => Some(e),
+            MapError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MapError {
+    fn from(e: io::Error) -> Self {
+        MapError::Io(e)
+    }
+}
+
+impl From<SaveError> for MapError {
+    fn from(e: SaveError) -> Self {
+        MapError::Save(e)
+    }
+}
+
+impl From<AzgaarError> for MapError {
+    fn from(e: AzgaarError) -> Self {
+        MapError::Azgaar(e)
+    }
+}
+
+impl From<ImageError> for MapError {
+    fn from(e: ImageError) -> Self {
+        MapError::Image(e)
+    }
+}
@@ -0,0 +1,252 @@
+//! Geometry on polygons and segments clipped to the map, and on the sphere.
+
+use triangulation::Point;
+
+use crate::Size;
+
+fn distance(a: Point, b: Point) -> f32 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+}
+
+/// Area and centroid of a simple polygon, either winding. A polygon without
+/// area has no centroid.
+pub fn area_centroid(polygon: &[Point]) -> (f32, Option<Point>) {
+    let mut area = 0.0;
+    let mut x = 0.0;
+    let mut y = 0.0;
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let cross = a.x * b.y - b.x * a.y;
+        area += cross;
+        x += (a.x + b.x) * cross;
+        y += (a.y + b.y) * cross;
+    }
+    if area.abs() < std::f32::EPSILON {
+        return (0.0, None);
+    }
+    (area.abs() / 2.0, Some(Point::new(x / (3.0 * area), y / (3.0 * area))))
+}
+
+pub fn perimeter(polygon: &[Point]) -> f32 {
+    polygon
+        .iter()
+        .enumerate()
+        .map(|(i, &a)| distance(a, polygon[(i + 1) % polygon.len()]))
+        .sum()
+}
+
+/// Whether `p` is inside the polygon, by the even-odd rule.
+pub fn contains(polygon: &[Point], p: Point) -> bool {
+    let mut inside = false;
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.y > p.y) != (b.y > p.y) && p.x < lerp_y(a, b, p.y).x {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// Sutherland–Hodgman clipping of a polygon to the map.
+pub fn clip_polygon(polygon: &[Point], size: Size) -> Vec<Point> {
+    let width = size.width as f32;
+    let polygon = clip_edge(polygon, |p| p.x >= 0.0, |a, b| lerp_x(a, b, 0.0));
+    let polygon = clip_edge(&polygon, |p| p.x <= width, |a, b| lerp_x(a, b, width));
+    clip_polygon_vertically(&polygon, size)
+}
+
+/// Clip a polygon to the top and bottom edges of the map only, for maps that
+/// wrap around horizontally.
+pub fn clip_polygon_vertically(polygon: &[Point], size: Size) -> Vec<Point> {
+    let height = size.height as f32;
+    let polygon = clip_edge(polygon, |p| p.y >= 0.0, |a, b| lerp_y(a, b, 0.0));
+    clip_edge(&polygon, |p| p.y <= height, |a, b| lerp_y(a, b, height))
+}
+
+// Keep the part of `polygon` that is `inside` of one edge of the map.
+fn clip_edge<I, X>(polygon: &[Point], inside: I, intersect: X) -> Vec<Point>
+where
+    I: Fn(Point) -> bool,
+    X: Fn(Point, Point) -> Point,
+{
+    let mut output = Vec::with_capacity(polygon.len() + 1);
+    for (i, &current) in polygon.iter().enumerate() {
+        let prev = polygon[(i + polygon.len() - 1) % polygon.len()];
+        match (inside(prev), inside(current)) {
+            (true, true) => output.push(current),
+            (true, false) => output.push(intersect(prev, current)),
+            (false, true) => {
+                output.push(intersect(prev, current));
+                output.push(current);
+            }
+            (false, false) => {}
+        }
+    }
+    output
+}
+
+// Point on the line through `a` and `b` with the given x.
+fn lerp_x(a: Point, b: Point, x: f32) -> Point {
+    let t = (x - a.x) / (b.x - a.x);
+    Point::new(x, a.y + t * (b.y - a.y))
+}
+
+// Point on the line through `a` and `b` with the given y.
+fn lerp_y(a: Point, b: Point, y: f32) -> Point {
+    let t = (y - a.y) / (b.y - a.y);
+    Point::new(a.x + t * (b.x - a.x), y)
+}
+
+/// Length of the part of segment `a`-`b` on the map, using Liang–Barsky
+/// clipping.
+pub fn clipped_length(a: Point, b: Point, size: Size) -> f32 {
+    let dx = b.x - a.x;
+    let dy = b.y - a.y;
+    liang_barsky(a, b, &[
+        (-dx, a.x),
+        (dx, size.width as f32 - a.x),
+        (-dy, a.y),
+        (dy, size.height as f32 - a.y),
+    ])
+}
+
+/// Length of the part of segment `a`-`b` between the top and bottom edges of
+/// the map.
+pub fn clipped_length_vertically(a: Point, b: Point, size: Size) -> f32 {
+    let dy = b.y - a.y;
+    liang_barsky(a, b, &[(-dy, a.y), (dy, size.height as f32 - a.y)])
+}
+
+// Length of the part of segment `a`-`b` inside all the `(p, q)` edges.
+fn liang_barsky(a: Point, b: Point, checks: &[(f32, f32)]) -> f32 {
+    let mut t0: f32 = 0.0;
+    let mut t1: f32 = 1.0;
+    for &(p, q) in checks {
+        if p == 0.0 {
+            if q < 0.0 {
+                return 0.0;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t0 = t0.max(t);
+            } else {
+                t1 = t1.min(t);
+            }
+        }
+    }
+    if t0 >= t1 {
+        return 0.0;
+    }
+    distance(a, b) * (t1 - t0)
+}
+
+/// A point or direction in space, for spherical grids.
+pub type Vec3 = [f64; 3];
+
+pub fn dot(a: Vec3, b: Vec3) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+pub fn normalize(a: Vec3) -> Vec3 {
+    let length = dot(a, a).sqrt();
+    [a[0] / length, a[1] / length, a[2] / length]
+}
+
+/// Great-circle distance between two points on the unit sphere.
+pub fn arc_length(a: Vec3, b: Vec3) -> f64 {
+    let c = cross(a, b);
+    dot(c, c).sqrt().atan2(dot(a, b))
+}
+
+/// Area of the spherical triangle between three points on the unit sphere,
+/// after Van Oosterom and Strackee.
+pub fn spherical_triangle_area(a: Vec3, b: Vec3, c: Vec3) -> f64 {
+    let numerator = dot(a, cross(b, c)).abs();
+    let denominator = 1.0 + dot(a, b) + dot(b, c) + dot(c, a);
+    2.0 * numerator.atan2(denominator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square(x: f32, y: f32, side: f32) -> Vec<Point> {
+        vec![
+            Point::new(x, y),
+            Point::new(x + side, y),
+            Point::new(x + side, y + side),
+            Point::new(x, y + side),
+        ]
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn square_area_centroid_and_perimeter() {
+        let mut polygon = square(10.0, 20.0, 4.0);
+        for _ in 0..2 {
+            let (area, centroid) = area_centroid(&polygon);
+            let centroid = centroid.unwrap();
+            assert_close(area, 16.0);
+            assert_close(centroid.x, 12.0);
+            assert_close(centroid.y, 22.0);
+            assert_close(perimeter(&polygon), 16.0);
+            polygon.reverse();
+        }
+    }
+
+    #[test]
+    fn degenerate_polygon_has_no_centroid() {
+        let line = [Point::new(0.0, 0.0), Point::new(1.0, 1.0), Point::new(2.0, 2.0)];
+        assert_eq!(area_centroid(&line), (0.0, None));
+    }
+
+    #[test]
+    fn square_contains_its_inside() {
+        let polygon = square(0.0, 0.0, 10.0);
+        assert!(contains(&polygon, Point::new(5.0, 5.0)));
+        assert!(contains(&polygon, Point::new(0.5, 9.5)));
+        assert!(!contains(&polygon, Point::new(-1.0, 5.0)));
+        assert!(!contains(&polygon, Point::new(5.0, 11.0)));
+    }
+
+    #[test]
+    fn clips_polygon_to_the_map() {
+        let size = Size::new(100, 50);
+        let (area, _) = area_centroid(&clip_polygon(&square(90.0, 40.0, 20.0), size));
+        assert_close(area, 100.0);
+        let (area, _) = area_centroid(&clip_polygon(&square(-10.0, -10.0, 120.0), size));
+        assert_close(area, 5000.0);
+        assert!(clip_polygon(&square(200.0, 0.0, 10.0), size).is_empty());
+    }
+
+    #[test]
+    fn clips_segments_to_the_map() {
+        let size = Size::new(100, 50);
+        assert_close(clipped_length(Point::new(10.0, 10.0), Point::new(40.0, 50.0), size), 50.0);
+        assert_close(clipped_length(Point::new(-50.0, 25.0), Point::new(150.0, 25.0), size), 100.0);
+        assert_close(clipped_length(Point::new(0.0, -30.0), Point::new(120.0, 60.0), size), 75.0);
+        assert_close(clipped_length(Point::new(110.0, 0.0), Point::new(110.0, 50.0), size), 0.0);
+    }
+
+    #[test]
+    fn clips_only_top_and_bottom_when_wrapping() {
+        let size = Size::new(100, 50);
+        let clipped = clip_polygon_vertically(&square(90.0, 40.0, 20.0), size);
+        assert!(clipped.iter().any(|p| p.x > 100.0));
+        assert_close(area_centroid(&clipped).0, 200.0);
+        assert_close(clipped_length_vertically(Point::new(-50.0, 25.0), Point::new(150.0, 25.0), size), 200.0);
+        assert_close(clipped_length_vertically(Point::new(90.0, 40.0), Point::new(110.0, 60.0), size), 800f32.sqrt() / 2.0);
+    }
+}
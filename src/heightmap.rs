@@ -1,21 +1,64 @@
+//! Heightmap generation from templates.
+//!
+//! The primitives are tuned for a grid of 10,000 cells. On other grids hills,
+//! ranges and straits spread over more or fewer cells, so that they cover the
+//! same part of the map.
+//!
+//! Heights run from 0 to `WORLD_MAX`, with land from `OCEAN_HEIGHT` up. The
+//! scale is only a convention for templates and display, `elevation` converts
+//! heights to meters.
+
+use std::cmp::Ordering;
 use std::collections::VecDeque;
+use std::fmt;
 use std::ops::{Bound, Range, RangeBounds};
-use std::num::NonZeroU32;
+use std::str::FromStr;
 
 use rand::Rng;
-use rand::distributions::Distribution;
-use rand::distributions::uniform::Uniform;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::distributions::uniform::{SampleUniform, Uniform};
 use rand::rngs::StdRng;
-use triangulation::PointIndex;
-
-use crate::Grid;
+use serde::{Deserialize, Serialize};
+
+use crate::{CancelToken, Grid, MapError};
+use crate::image::GrayImage;
+use crate::voronoi::CellIndex;
+
+pub const WORLD_MAX: f32 = 100.0;
+pub const OCEAN_HEIGHT: f32 = 20.0;
+
+// Depth in meters of the lowest height, the ocean floor is never deeper.
+const MAX_DEPTH: f32 = 990.0;
+
+/// Real-world elevation in meters of `height`, negative below sea level, as in
+/// the original generator. Land rises with its height above sea level to the
+/// power of `exponent`, the `height_exponent` of the options, so a larger one
+/// gives higher mountains.
+pub fn elevation(height: f32, exponent: f32) -> f32 {
+    if height >= OCEAN_HEIGHT {
+        // Even the lowest land is a bit above the water.
+        (height - OCEAN_HEIGHT + 2.0).powf(exponent)
+    } else if height > 0.0 {
+        ((height - OCEAN_HEIGHT) / height * 50.0).max(-MAX_DEPTH)
+    } else {
+        -MAX_DEPTH
+    }
+}
 
-pub const WORLD_MAX: u8 = 100;
-pub const OCEAN_HEIGHT: u8 = 20;
+/// `height` rounded to a whole height, for formats that only have those. Sea
+/// stays below `OCEAN_HEIGHT`, so the coastline does not move.
+pub(crate) fn whole_height(height: f32) -> u8 {
+    let rounded = height.round().max(0.0).min(WORLD_MAX);
+    if height < OCEAN_HEIGHT {
+        rounded.min(OCEAN_HEIGHT - 1.0) as u8
+    } else {
+        rounded as u8
+    }
+}
 
 // TODO: impl rand distribution
 // TODO: support custom template
-#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum Template {
     Archipelago,
     Atoll,
@@ -29,6 +72,136 @@ pub enum Template {
     Volcano,
 }
 
+impl Template {
+    pub const ALL: [Template; 10] = [
+        Template::Archipelago,
+        Template::Atoll,
+        Template::Continents,
+        Template::HighIsland,
+        Template::Isthmus,
+        Template::LowIsland,
+        Template::Mediterranean,
+        Template::Pangaea,
+        Template::Peninsula,
+        Template::Volcano,
+    ];
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Template::Archipelago => write!(f, "Archipelago"),
+            Template::Atoll => write!(f, "Atoll"),
+            Template::Continents => write!(f, "Continents"),
+            Template::HighIsland => write!(f, "High Island"),
+            Template::Isthmus => write!(f, "Isthmus"),
+            Template::LowIsland => write!(f, "Low Island"),
+            Template::Mediterranean => write!(f, "Mediterranean"),
+            Template::Pangaea => write!(f, "Pangaea"),
+            Template::Peninsula => write!(f, "Peninsula"),
+            Template::Volcano => write!(f, "Volcano"),
+        }
+    }
+}
+
+/// How often a template is picked when the options leave it open.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TemplateWeight {
+    pub template: Template,
+    pub weight: u32,
+}
+
+impl TemplateWeight {
+    pub fn new(template: Template, weight: u32) -> Self {
+        TemplateWeight {
+            template,
+            weight,
+        }
+    }
+
+    /// The weights of the original generator, favoring islands and
+    /// archipelagos over the more peculiar shapes.
+    pub fn defaults() -> Vec<TemplateWeight> {
+        vec![
+            TemplateWeight::new(Template::Volcano, 3),
+            TemplateWeight::new(Template::HighIsland, 22),
+            TemplateWeight::new(Template::LowIsland, 9),
+            TemplateWeight::new(Template::Continents, 16),
+            TemplateWeight::new(Template::Archipelago, 18),
+            TemplateWeight::new(Template::Mediterranean, 5),
+            TemplateWeight::new(Template::Peninsula, 3),
+            TemplateWeight::new(Template::Pangaea, 5),
+            TemplateWeight::new(Template::Isthmus, 2),
+            TemplateWeight::new(Template::Atoll, 1),
+        ]
+    }
+}
+
+/// Picks templates at random in proportion to their weights.
+#[derive(Clone, Debug)]
+pub struct TemplateDistribution {
+    templates: Vec<Template>,
+    index: WeightedIndex<u32>,
+}
+
+impl TemplateDistribution {
+    /// Fails if there are no weights or they are all zero.
+    pub fn new(weights: &[TemplateWeight]) -> Result<Self, MapError> {
+        let index = WeightedIndex::new(weights.iter().map(|w| w.weight))
+            .map_err(MapError::TemplateWeights)?;
+        Ok(TemplateDistribution {
+            templates: weights.iter().map(|w| w.template).collect(),
+            index,
+        })
+    }
+}
+
+impl Distribution<Template> for TemplateDistribution {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Template {
+        self.templates[self.index.sample(rng)]
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseTemplateError(String);
+
+impl fmt::Display for ParseTemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown heightmap template \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for ParseTemplateError {}
+
+impl FromStr for Template {
+    type Err = ParseTemplateError;
+
+    /// Parse a template name. Case, spaces, dashes and underscores are
+    /// ignored, so both "High Island" (as used by the page) and "high-island"
+    /// are accepted.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-' && *c != '_')
+            .flat_map(char::to_lowercase)
+            .collect();
+        match name.as_str() {
+            "archipelago" => Ok(Template::Archipelago),
+            "atoll" => Ok(Template::Atoll),
+            "continents" => Ok(Template::Continents),
+            "highisland" => Ok(Template::HighIsland),
+            "isthmus" => Ok(Template::Isthmus),
+            "lowisland" => Ok(Template::LowIsland),
+            "mediterranean" => Ok(Template::Mediterranean),
+            // The page spells it "Pangea".
+            "pangaea" | "pangea" => Ok(Template::Pangaea),
+            "peninsula" => Ok(Template::Peninsula),
+            "volcano" => Ok(Template::Volcano),
+            _ => Err(ParseTemplateError(s.to_owned())),
+        }
+    }
+}
+
 // Not really a word. Derived from "Cartesian coordinate system".
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub enum Cartesianality {
@@ -40,47 +213,50 @@ pub enum HeightRange {
     All,
     Land,
     Ocean,
+    /// Whole heights from the first up to and including the second, so
+    /// `Range(20, 99)` covers everything from 20 up to but not including 100.
     Range(u8, u8),
 }
 
 impl HeightRange {
-    fn max(&self) -> u8 {
+    fn contains(&self, height: f32) -> bool {
         match self {
-            HeightRange::All => WORLD_MAX,
-            HeightRange::Land => WORLD_MAX,
-            HeightRange::Ocean => OCEAN_HEIGHT.saturating_sub(1),
-            HeightRange::Range(_, end) => *end,
+            HeightRange::All => true,
+            HeightRange::Land => height >= OCEAN_HEIGHT,
+            HeightRange::Ocean => height < OCEAN_HEIGHT,
+            HeightRange::Range(start, end) => height >= *start as f32 && height < *end as f32 + 1.0,
         }
     }
 
-    fn min(&self) -> u8 {
+    fn min(&self) -> f32 {
         match self {
-            HeightRange::All => 0,
+            HeightRange::All => 0.0,
             HeightRange::Land => OCEAN_HEIGHT,
-            HeightRange::Ocean => 0,
-            HeightRange::Range(start, _) => *start,
+            HeightRange::Ocean => 0.0,
+            HeightRange::Range(start, _) => *start as f32,
         }
     }
 }
 
 impl<R: RangeBounds<u8>> From<R> for HeightRange {
     fn from(range: R) -> Self {
+        let max = WORLD_MAX as u8;
         match (range.start_bound(), range.end_bound()) {
             (Bound::Included(&s), Bound::Excluded(&e)) =>
                 HeightRange::Range(s, e.saturating_sub(1)),
             (Bound::Included(&s), Bound::Included(&e)) => HeightRange::Range(s, e),
-            (Bound::Included(&v), Bound::Unbounded) => HeightRange::Range(v, WORLD_MAX),
+            (Bound::Included(&v), Bound::Unbounded) => HeightRange::Range(v, max),
             (Bound::Excluded(&v), Bound::Unbounded) =>
-                HeightRange::Range((v + 1).min(WORLD_MAX), WORLD_MAX),
+                HeightRange::Range((v + 1).min(max), max),
             (Bound::Unbounded, Bound::Excluded(&v)) => HeightRange::Range(0, v.saturating_sub(1)),
             (Bound::Unbounded, Bound::Included(&v)) => HeightRange::Range(0, v),
             (Bound::Unbounded, Bound::Unbounded) => HeightRange::All,
 
             // I'm not sure this will ever be hit...
             (Bound::Excluded(&s), Bound::Excluded(&e)) =>
-                HeightRange::Range((s + 1).min(WORLD_MAX), e.saturating_sub(1)),
+                HeightRange::Range((s + 1).min(max), e.saturating_sub(1)),
             (Bound::Excluded(&s), Bound::Included(&e)) =>
-                HeightRange::Range((s + 1).min(WORLD_MAX), e),
+                HeightRange::Range((s + 1).min(max), e),
         }
     }
 }
@@ -88,162 +264,244 @@ impl<R: RangeBounds<u8>> From<R> for HeightRange {
 pub struct HeightmapGenerator;
 
 impl HeightmapGenerator {
+    /// Generate from a template picked at random by `weights`, returning the
+    /// one picked.
     pub fn generate(
         grid: &mut Grid,
         rng: &mut StdRng,
-    ) {
-        unimplemented!()
+        weights: &[TemplateWeight],
+        cancel: &CancelToken,
+    ) -> Result<Template, MapError> {
+        let template = TemplateDistribution::new(weights)?.sample(rng);
+        Self::generate_with_template(grid, rng, template, cancel)?;
+        Ok(template)
     }
 
+    /// Generate from `template`. Stops with `MapError::Cancelled` once
+    /// `cancel` is cancelled, checked for every hill, pit and ring of a range
+    /// or trough.
     pub fn generate_with_template(
         grid: &mut Grid,
         rng: &mut StdRng,
-        template: Template
-    ) {
+        template: Template,
+        cancel: &CancelToken,
+    ) -> Result<(), MapError> {
         // Clear the existing heights.
-        grid.heights = vec![0; grid.voronoi.cells.len()];
+        grid.heights = vec![0.0; grid.voronoi.cell_count()];
 
         match template {
-            Template::Archipelago => generate_archipelago(grid, rng),
-            Template::Atoll => generate_atoll(grid, rng),
-            Template::Continents => generate_continents(grid, rng),
-            Template::HighIsland => generate_high_island(grid, rng),
-            Template::Isthmus => generate_isthmus(grid, rng),
-            Template::LowIsland => generate_low_island(grid, rng),
-            Template::Mediterranean => generate_mediterranean(grid, rng),
-            Template::Pangaea => generate_pangaea(grid, rng),
-            Template::Peninsula => generate_peninsula(grid, rng),
-            Template::Volcano => generate_volcano(grid, rng),
+            Template::Archipelago => generate_archipelago(grid, rng, cancel),
+            Template::Atoll => generate_atoll(grid, rng, cancel),
+            Template::Continents => generate_continents(grid, rng, cancel),
+            Template::HighIsland => generate_high_island(grid, rng, cancel),
+            Template::Isthmus => generate_isthmus(grid, rng, cancel),
+            Template::LowIsland => generate_low_island(grid, rng, cancel),
+            Template::Mediterranean => generate_mediterranean(grid, rng, cancel),
+            Template::Pangaea => generate_pangaea(grid, rng, cancel),
+            Template::Peninsula => generate_peninsula(grid, rng, cancel),
+            Template::Volcano => generate_volcano(grid, rng, cancel),
         }
     }
+
+    /// Take the heights from the luminance of `image`, stretched over the map.
+    /// Luminance `sea_level`, from 0 to 1, becomes `OCEAN_HEIGHT`, so darker
+    /// pixels are ocean and lighter ones land. Both sides are scaled linearly
+    /// to the full range of heights.
+    pub fn generate_from_image(grid: &mut Grid, image: &GrayImage, sea_level: f32) {
+        let land = WORLD_MAX - OCEAN_HEIGHT;
+        grid.heights = grid.points
+            .iter()
+            .take(grid.voronoi.cell_count())
+            .map(|&p| {
+                let luminance = image.luminance(p, grid.size);
+                if luminance < sea_level {
+                    OCEAN_HEIGHT * luminance / sea_level
+                } else if sea_level < 1.0 {
+                    OCEAN_HEIGHT + land * (luminance - sea_level) / (1.0 - sea_level)
+                } else {
+                    WORLD_MAX
+                }
+            })
+            .collect();
+    }
 }
 
 // Template generation functions
-fn generate_archipelago(grid: &mut Grid, rng: &mut StdRng) {
-    add(grid, rng, HeightRange::All, 11);
-    range(grid, rng, 2.0..3.0, 40..60, 20.0..80.0, 20.0..80.0);
-    hill(grid, rng, 5.0.., 15..20, 10.0..90.0, 30.0..70.0);
-    hill(grid, rng, 2.0.., 10..15, 10.0..30.0, 20.0..80.0);
-    hill(grid, rng, 2.0.., 10..15, 60.0..90.0, 20.0..80.0);
+fn generate_archipelago(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    add(grid, rng, HeightRange::All, 11.0);
+    range(grid, rng, 2.0..3.0, 40.0..60.0, 20.0..80.0, 20.0..80.0, cancel)?;
+    hill(grid, rng, 5.0.., 15.0..20.0, 10.0..90.0, 30.0..70.0, cancel)?;
+    hill(grid, rng, 2.0.., 10.0..15.0, 10.0..30.0, 20.0..80.0, cancel)?;
+    hill(grid, rng, 2.0.., 10.0..15.0, 60.0..90.0, 20.0..80.0, cancel)?;
     smooth(grid, rng, 3);
-    trough(grid, rng, 10.0.., 20..30, 5.0..95.0, 5.0..95.0);
+    trough(grid, rng, 10.0.., 20.0..30.0, 5.0..95.0, 5.0..95.0, cancel)?;
     strait(grid, rng, 2.0.., Cartesianality::Vertical);
     strait(grid, rng, 2.0.., Cartesianality::Horizontal);
+    Ok(())
 }
 
-fn generate_atoll(grid: &mut Grid, rng: &mut StdRng) {
-    hill(grid, rng, 1.0.., 75..80, 50.0..60.0, 45.0..55.0);
-    hill(grid, rng, 1.5.., 30..50, 25.0..75.0, 30.0..70.0);
-    hill(grid, rng, 0.5.., 30..50, 25.0..35.0, 30.0..70.0);
+fn generate_atoll(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    hill(grid, rng, 1.0.., 75.0..80.0, 50.0..60.0, 45.0..55.0, cancel)?;
+    hill(grid, rng, 1.5.., 30.0..50.0, 25.0..75.0, 30.0..70.0, cancel)?;
+    hill(grid, rng, 0.5.., 30.0..50.0, 25.0..35.0, 30.0..70.0, cancel)?;
     smooth(grid, rng, 1);
     multiply(grid, rng, (25..100).into(), 0.2);
-    hill(grid, rng, 0.5.., 10..20, 50.0..55.0, 48.0..52.0);
+    hill(grid, rng, 0.5.., 10.0..20.0, 50.0..55.0, 48.0..52.0, cancel)?;
+    Ok(())
 }
 
-fn generate_continents(grid: &mut Grid, rng: &mut StdRng) {
-    hill(grid, rng, 1.0.., 80..85, 75.0..80.0, 40.0..60.0);
-    hill(grid, rng, 1.0.., 80..85, 20.0..25.0, 40.0..60.0);
+fn generate_continents(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    hill(grid, rng, 1.0.., 80.0..85.0, 75.0..80.0, 40.0..60.0, cancel)?;
+    hill(grid, rng, 1.0.., 80.0..85.0, 20.0..25.0, 40.0..60.0, cancel)?;
     multiply(grid, rng, (20..100).into(), 0.22);
-    hill(grid, rng, 5.0..6.0, 15..20, 25.0..75.0, 20.0..82.0);
-    range(grid, rng, 0.8.., 30..60, 5.0..15.0, 20.0..45.0);
-    range(grid, rng, 0.8.., 30..60, 5.0..15.0, 55.0..80.0);
-    range(grid, rng, 0.0..3.0, 30..60, 80.0..90.0, 20.0..80.0);
-    trough(grid, rng, 3.0..4.0, 15..20, 15.0..85.0, 20.0..80.0);
+    hill(grid, rng, 5.0..6.0, 15.0..20.0, 25.0..75.0, 20.0..82.0, cancel)?;
+    range(grid, rng, 0.8.., 30.0..60.0, 5.0..15.0, 20.0..45.0, cancel)?;
+    range(grid, rng, 0.8.., 30.0..60.0, 5.0..15.0, 55.0..80.0, cancel)?;
+    range(grid, rng, 0.0..3.0, 30.0..60.0, 80.0..90.0, 20.0..80.0, cancel)?;
+    trough(grid, rng, 3.0..4.0, 15.0..20.0, 15.0..85.0, 20.0..80.0, cancel)?;
     strait(grid, rng, 2.0.., Cartesianality::Vertical);
     smooth(grid, rng, 2);
-    trough(grid, rng, 1.0..2.0, 5..10, 45.0..55.0, 45.0..55.0);
-    pit(grid, rng, 3.0..4.0, 10..15, 15.0..85.0, 20.0..80.0);
-    hill(grid, rng, 1.0.., 5..10, 40.0..60.0, 40.0..60.0);
+    trough(grid, rng, 1.0..2.0, 5.0..10.0, 45.0..55.0, 45.0..55.0, cancel)?;
+    pit(grid, rng, 3.0..4.0, 10.0..15.0, 15.0..85.0, 20.0..80.0, cancel)?;
+    hill(grid, rng, 1.0.., 5.0..10.0, 40.0..60.0, 40.0..60.0, cancel)?;
+    Ok(())
 }
 
-fn generate_high_island(grid: &mut Grid, rng: &mut StdRng) {
-    hill(grid, rng, 1.0.., 90..100, 65.0..75.0, 47.0..53.0);
-    add(grid, rng, HeightRange::All.into(), 5);
-    hill(grid, rng, 6.0.., 20..23, 25.0..55.0, 45.0..55.0);
-    range(grid, rng, 1.0.., 40..50, 45.0..55.0, 45.0..55.0);
+fn generate_high_island(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    hill(grid, rng, 1.0.., 90.0..100.0, 65.0..75.0, 47.0..53.0, cancel)?;
+    add(grid, rng, HeightRange::All.into(), 5.0);
+    hill(grid, rng, 6.0.., 20.0..23.0, 25.0..55.0, 45.0..55.0, cancel)?;
+    range(grid, rng, 1.0.., 40.0..50.0, 45.0..55.0, 45.0..55.0, cancel)?;
     smooth(grid, rng, 2);
-    trough(grid, rng, 2.0..3.0, 20..30, 20.0..30.0, 20.0..30.0);
-    trough(grid, rng, 2.0..3.0, 20..30, 60.0..80.0, 70.0..80.0);
-    hill(grid, rng, 1.0.., 10..15, 60.0..60.0, 50.0..50.0);
-    hill(grid, rng, 1.5.., 13..16, 15.0..20.0, 20.0..75.0);
+    trough(grid, rng, 2.0..3.0, 20.0..30.0, 20.0..30.0, 20.0..30.0, cancel)?;
+    trough(grid, rng, 2.0..3.0, 20.0..30.0, 60.0..80.0, 70.0..80.0, cancel)?;
+    hill(grid, rng, 1.0.., 10.0..15.0, 60.0..60.0, 50.0..50.0, cancel)?;
+    hill(grid, rng, 1.5.., 13.0..16.0, 15.0..20.0, 20.0..75.0, cancel)?;
     multiply(grid, rng, (20..100).into(), 0.8);
-    range(grid, rng, 1.5.., 30..40, 15.0..85.0, 30.0..40.0);
-    range(grid, rng, 1.5.., 30..40, 15.0..85.0, 60.0..70.0);
-    pit(grid, rng, 2.0..3.0, 10..15, 15.0..85.0, 20.0..80.0);
+    range(grid, rng, 1.5.., 30.0..40.0, 15.0..85.0, 30.0..40.0, cancel)?;
+    range(grid, rng, 1.5.., 30.0..40.0, 15.0..85.0, 60.0..70.0, cancel)?;
+    pit(grid, rng, 2.0..3.0, 10.0..15.0, 15.0..85.0, 20.0..80.0, cancel)?;
+    Ok(())
 }
 
-fn generate_isthmus(grid: &mut Grid, rng: &mut StdRng) {
-    hill(grid, rng, 5.0..10.0, 15..30, 0.0..30.0, 0.0..20.0);
-    hill(grid, rng, 5.0..10.0, 15..30, 10.0..50.0, 20.0..40.0);
-    hill(grid, rng, 5.0..10.0, 15..30, 30.0..70.0, 40.0..60.0);
-    hill(grid, rng, 5.0..10.0, 15..30, 50.0..90.0, 60.0..80.0);
-    hill(grid, rng, 5.0..10.0, 15..30, 70.0..100.0, 80.0..100.00);
+fn generate_isthmus(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    hill(grid, rng, 5.0..10.0, 15.0..30.0, 0.0..30.0, 0.0..20.0, cancel)?;
+    hill(grid, rng, 5.0..10.0, 15.0..30.0, 10.0..50.0, 20.0..40.0, cancel)?;
+    hill(grid, rng, 5.0..10.0, 15.0..30.0, 30.0..70.0, 40.0..60.0, cancel)?;
+    hill(grid, rng, 5.0..10.0, 15.0..30.0, 50.0..90.0, 60.0..80.0, cancel)?;
+    hill(grid, rng, 5.0..10.0, 15.0..30.0, 70.0..100.0, 80.0..100.00, cancel)?;
     smooth(grid, rng, 2);
-    trough(grid, rng, 4.0..8.0, 15..30, 0.0..30.0, 0.0..20.0);
-    trough(grid, rng, 4.0..8.0, 15..30, 10.0..50.0, 20.0..40.0);
-    trough(grid, rng, 4.0..8.0, 15..30, 30.0..70.0, 40.0..60.0);
-    trough(grid, rng, 4.0..8.0, 15..30, 50.0..90.0, 60.0..80.0);
-    trough(grid, rng, 4.0..8.0, 15..30, 70.0..100.0, 80.0..100.00);
+    trough(grid, rng, 4.0..8.0, 15.0..30.0, 0.0..30.0, 0.0..20.0, cancel)?;
+    trough(grid, rng, 4.0..8.0, 15.0..30.0, 10.0..50.0, 20.0..40.0, cancel)?;
+    trough(grid, rng, 4.0..8.0, 15.0..30.0, 30.0..70.0, 40.0..60.0, cancel)?;
+    trough(grid, rng, 4.0..8.0, 15.0..30.0, 50.0..90.0, 60.0..80.0, cancel)?;
+    trough(grid, rng, 4.0..8.0, 15.0..30.0, 70.0..100.0, 80.0..100.00, cancel)?;
+    Ok(())
 }
 
-fn generate_low_island(grid: &mut Grid, rng: &mut StdRng) {
-    hill(grid, rng, 1.0.., 90..99, 60.0..80.0, 45.0..55.0);
-    hill(grid, rng, 4.0..5.0, 25..35, 20.0..65.0, 40.0..60.0);
-    range(grid, rng, 1.0.., 40..50, 45.0..55.0, 45.0..55.0);
+fn generate_low_island(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    hill(grid, rng, 1.0.., 90.0..99.0, 60.0..80.0, 45.0..55.0, cancel)?;
+    hill(grid, rng, 4.0..5.0, 25.0..35.0, 20.0..65.0, 40.0..60.0, cancel)?;
+    range(grid, rng, 1.0.., 40.0..50.0, 45.0..55.0, 45.0..55.0, cancel)?;
     smooth(grid, rng, 3);
-    trough(grid, rng, 1.5.., 20..30, 15.0..85.0, 20.0..30.0);
-    trough(grid, rng, 1.5.., 20..30, 15.0..85.0, 70.0..80.0);
-    hill(grid, rng, 1.5.., 10..15, 5.0..15.0, 20.0..80.0);
-    hill(grid, rng, 1.0.., 10..15, 85.0..95.0, 70.0..80.0);
-    pit(grid, rng, 3.0..5.0, 10..15, 15.0..85.0, 20.0..80.0);
+    trough(grid, rng, 1.5.., 20.0..30.0, 15.0..85.0, 20.0..30.0, cancel)?;
+    trough(grid, rng, 1.5.., 20.0..30.0, 15.0..85.0, 70.0..80.0, cancel)?;
+    hill(grid, rng, 1.5.., 10.0..15.0, 5.0..15.0, 20.0..80.0, cancel)?;
+    hill(grid, rng, 1.0.., 10.0..15.0, 85.0..95.0, 70.0..80.0, cancel)?;
+    pit(grid, rng, 3.0..5.0, 10.0..15.0, 15.0..85.0, 20.0..80.0, cancel)?;
     multiply(grid, rng, (20..100).into(), 0.4);
+    Ok(())
 }
 
-fn generate_mediterranean(grid: &mut Grid, rng: &mut StdRng) {
-    range(grid, rng, 3.0..4.0, 30..50, 0.0..100.0, 0.0..10.0);
-    range(grid, rng, 3.0..4.0, 30..50, 0.0..100.0, 90.0..100.0);
-    hill(grid, rng, 5.0..6.0, 30..70, 0.0..100.0, 0.0..5.0);
-    hill(grid, rng, 5.0..6.0, 30..70, 0.0..100.0, 95.0..100.0);
+fn generate_mediterranean(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    range(grid, rng, 3.0..4.0, 30.0..50.0, 0.0..100.0, 0.0..10.0, cancel)?;
+    range(grid, rng, 3.0..4.0, 30.0..50.0, 0.0..100.0, 90.0..100.0, cancel)?;
+    hill(grid, rng, 5.0..6.0, 30.0..70.0, 0.0..100.0, 0.0..5.0, cancel)?;
+    hill(grid, rng, 5.0..6.0, 30.0..70.0, 0.0..100.0, 95.0..100.0, cancel)?;
     smooth(grid, rng, 1);
-    hill(grid, rng, 2.0..3.0, 30..70, 0.0..5.0, 20.0..80.0);
-    hill(grid, rng, 2.0..3.0, 30..70, 95.0..100.0, 20.0..80.0);
+    hill(grid, rng, 2.0..3.0, 30.0..70.0, 0.0..5.0, 20.0..80.0, cancel)?;
+    hill(grid, rng, 2.0..3.0, 30.0..70.0, 95.0..100.0, 20.0..80.0, cancel)?;
     multiply(grid, rng, HeightRange::Land.into(), 0.8);
-    trough(grid, rng, 3.0..5.0, 40..50, 0.0..100.0, 0.0..10.0);
-    trough(grid, rng, 3.0..5.0, 40..50, 0.0..100.0, 90.0..100.0);
+    trough(grid, rng, 3.0..5.0, 40.0..50.0, 0.0..100.0, 0.0..10.0, cancel)?;
+    trough(grid, rng, 3.0..5.0, 40.0..50.0, 0.0..100.0, 90.0..100.0, cancel)?;
+    Ok(())
 }
 
-fn generate_pangaea(grid: &mut Grid, rng: &mut StdRng) {
-    hill(grid, rng, 1.0..2.0, 25..40, 15.0..50.0, 0.0..10.0);
-    hill(grid, rng, 1.0..2.0, 5..40, 50.0..85.0, 0.0..10.0);
-    hill(grid, rng, 1.0..2.0, 25..40, 50.0..85.0, 90.0..100.0);
-    hill(grid, rng, 1.0..2.0, 5..40, 15.0..50.0, 90.0..100.0);
-    hill(grid, rng, 8.0..12.0, 20..40, 20.0..80.0, 48.0..52.0);
+fn generate_pangaea(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    hill(grid, rng, 1.0..2.0, 25.0..40.0, 15.0..50.0, 0.0..10.0, cancel)?;
+    hill(grid, rng, 1.0..2.0, 5.0..40.0, 50.0..85.0, 0.0..10.0, cancel)?;
+    hill(grid, rng, 1.0..2.0, 25.0..40.0, 50.0..85.0, 90.0..100.0, cancel)?;
+    hill(grid, rng, 1.0..2.0, 5.0..40.0, 15.0..50.0, 90.0..100.0, cancel)?;
+    hill(grid, rng, 8.0..12.0, 20.0..40.0, 20.0..80.0, 48.0..52.0, cancel)?;
     smooth(grid, rng, 2);
     multiply(grid, rng, HeightRange::Land.into(), 0.7);
-    trough(grid, rng, 3.0..4.0, 25..35, 5.0..95.0, 10.0..20.0);
-    trough(grid, rng, 3.0..4.0, 25..35, 5.0..95.0, 80.0..90.0);
-    range(grid, rng, 5.0..6.0, 30..40, 10.0..90.0, 35.0..65.0);
-}
-
-fn generate_peninsula(grid: &mut Grid, rng: &mut StdRng) {
-    range(grid, rng, 2.0..3.0, 20..35, 40.0..50.0, 0.0..15.0);
-    add(grid, rng, HeightRange::All.into(), 5);
-    hill(grid, rng, 1.0.., 90..100, 10.0..90.0, 0.0..5.0);
-    add(grid, rng, HeightRange::All.into(), 13);
-    hill(grid, rng, 3.0..4.0, 3..5, 5.0..95.0, 80.0..100.0);
-    hill(grid, rng, 1.0..2.0, 3..5, 5.0..95.0, 40.0..60.0);
-    trough(grid, rng, 5.0..6.0, 10..25, 5.0..95.0, 5.0..95.0);
+    trough(grid, rng, 3.0..4.0, 25.0..35.0, 5.0..95.0, 10.0..20.0, cancel)?;
+    trough(grid, rng, 3.0..4.0, 25.0..35.0, 5.0..95.0, 80.0..90.0, cancel)?;
+    range(grid, rng, 5.0..6.0, 30.0..40.0, 10.0..90.0, 35.0..65.0, cancel)?;
+    Ok(())
+}
+
+fn generate_peninsula(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    range(grid, rng, 2.0..3.0, 20.0..35.0, 40.0..50.0, 0.0..15.0, cancel)?;
+    add(grid, rng, HeightRange::All.into(), 5.0);
+    hill(grid, rng, 1.0.., 90.0..100.0, 10.0..90.0, 0.0..5.0, cancel)?;
+    add(grid, rng, HeightRange::All.into(), 13.0);
+    hill(grid, rng, 3.0..4.0, 3.0..5.0, 5.0..95.0, 80.0..100.0, cancel)?;
+    hill(grid, rng, 1.0..2.0, 3.0..5.0, 5.0..95.0, 40.0..60.0, cancel)?;
+    trough(grid, rng, 5.0..6.0, 10.0..25.0, 5.0..95.0, 5.0..95.0, cancel)?;
     smooth(grid, rng, 3);
+    Ok(())
 }
 
-fn generate_volcano(grid: &mut Grid, rng: &mut StdRng) {
-    hill(grid, rng, 1.0.., 90..100, 44.0..56.0, 40.0..60.0);
+fn generate_volcano(
+    grid: &mut Grid,
+    rng: &mut StdRng,
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    hill(grid, rng, 1.0.., 90.0..100.0, 44.0..56.0, 40.0..60.0, cancel)?;
     multiply(grid, rng, (50..100).into(), 8.0);
-    range(grid, rng, 1.5.., 30..55, 45.0..55.0, 40.0..60.0);
+    range(grid, rng, 1.5.., 30.0..55.0, 45.0..55.0, 40.0..60.0, cancel)?;
     smooth(grid, rng, 2);
-    hill(grid, rng, 1.5.., 25..35, 25.0..30.0, 20.0..75.0);
-    hill(grid, rng, 1.0.., 25..35, 75.0..80.0, 25.0..75.0);
-    hill(grid, rng, 0.5.., 20..25, 10.0..15.0, 20.0..25.0);
+    hill(grid, rng, 1.5.., 25.0..35.0, 25.0..30.0, 20.0..75.0, cancel)?;
+    hill(grid, rng, 1.0.., 25.0..35.0, 75.0..80.0, 25.0..75.0, cancel)?;
+    hill(grid, rng, 0.5.., 20.0..25.0, 10.0..15.0, 20.0..25.0, cancel)?;
+    Ok(())
 }
 
 // Feature generation functions
@@ -261,12 +519,13 @@ fn hill<C: RangeBounds<f32>>(
     // Number of hills to place, more or less
     count: C,
     // Amount to move height up by to create the center point of the hill
-    change_height: Range<u8>,
+    change_height: Range<f32>,
     // Horizontal range to place hills in by percent of horizontal size
     range_x: Range<f32>,
     // Vertical range to place hills in by percent of vertical size
     range_y: Range<f32>,
-) {
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
     alter_point(
         grid,
         rng,
@@ -275,6 +534,7 @@ fn hill<C: RangeBounds<f32>>(
         range_x,
         range_y,
         ModifyDirection::Raise,
+        cancel,
     )
 }
 
@@ -282,10 +542,11 @@ fn pit<C: RangeBounds<f32>>(
     grid: &mut Grid,
     rng: &mut StdRng,
     count: C,
-    change_height: Range<u8>,
+    change_height: Range<f32>,
     range_x: Range<f32>,
     range_y: Range<f32>,
-) {
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
     alter_point(
         grid,
         rng,
@@ -294,6 +555,7 @@ fn pit<C: RangeBounds<f32>>(
         range_x,
         range_y,
         ModifyDirection::Lower,
+        cancel,
     )
 }
 
@@ -301,40 +563,39 @@ fn alter_point<C: RangeBounds<f32>>(
     grid: &mut Grid,
     rng: &mut StdRng,
     count: C,
-    change_height: Range<u8>,
+    change_height: Range<f32>,
     range_x: Range<f32>,
     range_y: Range<f32>,
     direction: ModifyDirection,
-) {
-    let cells = &grid.voronoi.cells;
-
-    let count = match (count.start_bound(), count.end_bound()) {
-        (Bound::Included(s), Bound::Excluded(e)) => Uniform::new(s, e).sample(rng),
-        (Bound::Included(s), Bound::Included(e)) => Uniform::new_inclusive(s, e).sample(rng),
-        (Bound::Included(v), Bound::Unbounded)
-        | (Bound::Excluded(v), Bound::Unbounded)
-        | (Bound::Unbounded, Bound::Excluded(v))
-        | (Bound::Unbounded, Bound::Included(v)) => *v,
-        _ => unreachable!(),
-    };
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    let voronoi = &grid.voronoi;
+
+    let count = sample_range(rng, &count);
     let count = if rng.gen::<f32>() < count.fract() {
         count.trunc() as u32 + 1
     } else {
         count.trunc() as u32
     };
 
-    let power = get_blob_power(grid.density);
-    let height_uniform = Uniform::from(change_height);
+    // A blob loses a share of its height and a fixed amount with every ring
+    // of cells. The amount stands in for the rounding to whole heights that
+    // the primitives were tuned with on the reference grid.
+    let scale = cell_scale(grid);
+    let power = 1.0 - BLOB_DECAY / scale;
+    let loss = 0.5 / scale;
+    let height_uniform = uniform(change_height.start, change_height.end);
     let change_uniform = Uniform::new(0.9, 1.1);
-    let x_uniform = Uniform::new(
-            range_x.start * grid.size.width as f32 / 100.0,
+    let x_uniform = uniform(
+        range_x.start * grid.size.width as f32 / 100.0,
             range_x.end * grid.size.width as f32 / 100.0,
     );
-    let y_uniform = Uniform::new(
+    let y_uniform = uniform(
         range_y.start * grid.size.height as f32 / 100.0,
         range_y.end * grid.size.height as f32 / 100.0,
     );
     for _ in 0..count {
+        cancel.check()?;
         let h = height_uniform.sample(rng).min(WORLD_MAX);
 
         let mut start = 0;
@@ -346,7 +607,7 @@ fn alter_point<C: RangeBounds<f32>>(
             start = grid.coords_to_cell_index(x, y).into();
 
             let good = match direction {
-                ModifyDirection::Raise => grid.heights[start] + h <= ((WORLD_MAX as u32 * 9) / 10) as u8,
+                ModifyDirection::Raise => grid.heights[start] + h <= WORLD_MAX * 0.9,
                 // TODO: alter stuff below the ocean as well
                 ModifyDirection::Lower => grid.heights[start] >= OCEAN_HEIGHT,
             };
@@ -355,67 +616,61 @@ fn alter_point<C: RangeBounds<f32>>(
             }
         }
 
-        let mut change = vec![0; cells.len()];
+        let mut change = vec![0.0; voronoi.cell_count()];
         change[start] = h;
         let mut queue = VecDeque::new();
         queue.push_back(start);
         while !queue.is_empty() {
             let q = queue.pop_front().unwrap();
             let h = match direction {
-                ModifyDirection::Raise => (change[q] as f32).powf(power),
+                ModifyDirection::Raise => change[q].powf(power),
                 // TODO: how does removing this extra randomization affect things?
-                ModifyDirection::Lower => (change[q] as f32).powf(power) * change_uniform.sample(rng),
+                ModifyDirection::Lower => change[q].powf(power) * change_uniform.sample(rng),
             };
 
-            for adjacent in cells[&q.into()].adjacent_cells.iter() {
-                if change[adjacent.as_usize()] != 0 {
+            for adjacent in voronoi.cell(q.into()).adjacent_cells.iter() {
+                if change[adjacent.as_usize()] != 0.0 {
                     continue;
                 }
-                change[adjacent.as_usize()] = (h * change_uniform.sample(rng)) as u8;
-                if change[adjacent.as_usize()] > 1 {
+                change[adjacent.as_usize()] = (h * change_uniform.sample(rng) - loss).max(0.0);
+                if change[adjacent.as_usize()] > 1.0 {
                     queue.push_back(adjacent.as_usize())
                 }
             }
         }
 
-        for i in 0..grid.heights.len() {
-            match direction {
-                ModifyDirection::Raise => grid.heights[i] = grid.heights[i].saturating_add(change[i]),
-                ModifyDirection::Lower => grid.heights[i] = grid.heights[i].saturating_sub(change[i]),
-            }
+        for (height, change) in grid.heights.iter_mut().zip(change) {
+            *height = match direction {
+                ModifyDirection::Raise => (*height + change).min(WORLD_MAX),
+                ModifyDirection::Lower => (*height - change).max(0.0),
+            };
         }
     }
+    Ok(())
 }
 
 fn range<C: RangeBounds<f32>>(
     grid: &mut Grid,
     rng: &mut StdRng,
     count: C,
-    change_height: Range<u8>,
+    change_height: Range<f32>,
     range_x: Range<f32>,
     range_y: Range<f32>,
-) {
-    let count = match (count.start_bound(), count.end_bound()) {
-        (Bound::Included(s), Bound::Excluded(e)) => Uniform::new(s, e).sample(rng),
-        (Bound::Included(s), Bound::Included(e)) => Uniform::new_inclusive(s, e).sample(rng),
-        (Bound::Included(v), Bound::Unbounded)
-        | (Bound::Excluded(v), Bound::Unbounded)
-        | (Bound::Unbounded, Bound::Excluded(v))
-        | (Bound::Unbounded, Bound::Included(v)) => *v,
-        _ => unreachable!(),
-    };
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    let count = sample_range(rng, &count);
     let count = if rng.gen::<f32>() < count.fract() {
         count.trunc() as u32 + 1
     } else {
         count.trunc() as u32
     };
 
-    let height_uniform = Uniform::from(change_height);
-    let start_x_uniform = Uniform::new(
+    let height_uniform = uniform(change_height.start, change_height.end);
+    let start_x_uniform = uniform(
         range_x.start * grid.size.width as f32 / 100.0,
         range_x.end * grid.size.width as f32 / 100.0,
     );
-    let start_y_uniform = Uniform::new(
+    let start_y_uniform = uniform(
         range_y.start * grid.size.height as f32 / 100.0,
         range_y.end * grid.size.height as f32 / 100.0,
     );
@@ -425,7 +680,7 @@ fn range<C: RangeBounds<f32>>(
         grid.size.height as f32 * 0.85,
     );
     for _ in 0..count {
-        let mut h = height_uniform.sample(rng).min(WORLD_MAX) as f32;
+        let mut h = height_uniform.sample(rng).min(WORLD_MAX);
 
         let start_x = start_x_uniform.sample(rng);
         let start_y = start_y_uniform.sample(rng);
@@ -441,7 +696,7 @@ fn range<C: RangeBounds<f32>>(
             }
         }
 
-        let mut used = vec![false; grid.voronoi.cells.len()];
+        let mut used = vec![false; grid.voronoi.cell_count()];
         let range = get_range(
             grid,
             &mut used,
@@ -451,26 +706,28 @@ fn range<C: RangeBounds<f32>>(
             0.85,
         );
 
-        let power = get_line_power(grid.density);
+        let scale = cell_scale(grid);
+        let power = 1.0 - LINE_DECAY / scale;
         let mut queue = range.clone();
         let mut ridge_depth = 0;
         while !queue.is_empty() {
+            cancel.check()?;
             let mut new_queue = VecDeque::new();
             ridge_depth += 1;
 
-            let change_uniform = Uniform::new(0.85, h * 0.3 + 0.85);
+            let change_uniform = uniform(0.85, h * 0.3 + 0.85);
             for idx in &queue {
-                grid.heights[idx.as_usize()] =
-                    ((grid.heights[idx.as_usize()] as f32 + change_uniform.sample(rng)) as u8).min(WORLD_MAX);
+                let height = &mut grid.heights[idx.as_usize()];
+                *height = (*height + change_uniform.sample(rng)).min(WORLD_MAX);
             }
 
-            h = h.powf(power) - 1.0;
+            h = h.powf(power) - 1.0 / scale;
             if h < 2.0 {
                 break;
             }
 
             for idx in &queue {
-                for &adjacent in &grid.voronoi.cells[idx].adjacent_cells {
+                for &adjacent in grid.voronoi.cell(*idx).adjacent_cells {
                     if !used[adjacent.as_usize()] {
                         new_queue.push_back(adjacent);
                         used[adjacent.as_usize()] = true;
@@ -482,57 +739,54 @@ fn range<C: RangeBounds<f32>>(
         }
 
         // generate prominences
+        let prominence_step = ((PROMINENCE_STEP * scale).round() as usize).max(1);
         for (d, cell) in range.iter().enumerate() {
             let mut cur = cell;
-            if d % 6 != 0 {
+            if d % prominence_step != 0 {
                 continue;
             }
             for _ in 0..ridge_depth {
                 // Find the downhill cell.
                 let min = grid
                     .voronoi
-                    .cells[cur]
+                    .cell(*cur)
                     .adjacent_cells
                     .iter()
-                    .min_by(|a, b| grid.heights[a.as_usize()].cmp(&grid.heights[b.as_usize()]))
+                    .min_by(|a, b| {
+                        let (a, b) = (grid.heights[a.as_usize()], grid.heights[b.as_usize()]);
+                        a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                    })
                     .unwrap();
-                grid.heights[min.as_usize()] =
-                    ((grid.heights[cur.as_usize()] as u32 * 2 + grid.heights[min.as_usize()] as u32) / 3) as u8;
+                grid.heights[min.as_usize()] = (grid.heights[cur.as_usize()] * 2.0 + grid.heights[min.as_usize()]) / 3.0;
                 cur = min;
             }
         }
     }
+    Ok(())
 }
 
 fn trough<C: RangeBounds<f32>>(
     grid: &mut Grid,
     rng: &mut StdRng,
     count: C,
-    change_height: Range<u8>,
+    change_height: Range<f32>,
     range_x: Range<f32>,
     range_y: Range<f32>,
-) {
-    let count = match (count.start_bound(), count.end_bound()) {
-        (Bound::Included(s), Bound::Excluded(e)) => Uniform::new(s, e).sample(rng),
-        (Bound::Included(s), Bound::Included(e)) => Uniform::new_inclusive(s, e).sample(rng),
-        (Bound::Included(v), Bound::Unbounded)
-        | (Bound::Excluded(v), Bound::Unbounded)
-        | (Bound::Unbounded, Bound::Excluded(v))
-        | (Bound::Unbounded, Bound::Included(v)) => *v,
-        _ => unreachable!(),
-    };
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
+    let count = sample_range(rng, &count);
     let count = if rng.gen::<f32>() < count.fract() {
         count.trunc() as u32 + 1
     } else {
         count.trunc() as u32
     };
 
-    let height_uniform = Uniform::from(change_height);
-    let start_x_uniform = Uniform::new(
+    let height_uniform = uniform(change_height.start, change_height.end);
+    let start_x_uniform = uniform(
         range_x.start * grid.size.width as f32 / 100.0,
         range_x.end * grid.size.width as f32 / 100.0,
     );
-    let start_y_uniform = Uniform::new(
+    let start_y_uniform = uniform(
         range_y.start * grid.size.height as f32 / 100.0,
         range_y.end * grid.size.height as f32 / 100.0,
     );
@@ -542,7 +796,7 @@ fn trough<C: RangeBounds<f32>>(
         grid.size.height as f32 * 0.85,
     );
     for _ in 0..count {
-        let mut h = height_uniform.sample(rng).min(WORLD_MAX) as f32;
+        let mut h = height_uniform.sample(rng).min(WORLD_MAX);
 
         let mut start_x = 0.0;
         let mut start_y = 0.0;
@@ -566,7 +820,7 @@ fn trough<C: RangeBounds<f32>>(
             }
         }
 
-        let mut used = vec![false; grid.voronoi.cells.len()];
+        let mut used = vec![false; grid.voronoi.cell_count()];
         let range = get_range(
             grid,
             &mut used,
@@ -576,26 +830,28 @@ fn trough<C: RangeBounds<f32>>(
             0.8,
         );
 
-        let power = get_line_power(grid.density);
+        let scale = cell_scale(grid);
+        let power = 1.0 - LINE_DECAY / scale;
         let mut queue = range.clone();
         let mut ridge_depth = 0;
         while !queue.is_empty() {
+            cancel.check()?;
             let mut new_queue = VecDeque::new();
             ridge_depth += 1;
 
-            let change_uniform = Uniform::new(0.85, h * 0.3 + 0.85);
+            let change_uniform = uniform(0.85, h * 0.3 + 0.85);
             for idx in &queue {
-                grid.heights[idx.as_usize()] =
-                    ((grid.heights[idx.as_usize()] as f32 - change_uniform.sample(rng)) as u8).min(WORLD_MAX);
+                let height = &mut grid.heights[idx.as_usize()];
+                *height = (*height - change_uniform.sample(rng)).max(0.0);
             }
 
-            h = h.powf(power) - 1.0;
+            h = h.powf(power) - 1.0 / scale;
             if h < 2.0 {
                 break;
             }
 
             for idx in &queue {
-                for &adjacent in &grid.voronoi.cells[idx].adjacent_cells {
+                for &adjacent in grid.voronoi.cell(*idx).adjacent_cells {
                     if !used[adjacent.as_usize()] {
                         new_queue.push_back(adjacent);
                         used[adjacent.as_usize()] = true;
@@ -607,45 +863,48 @@ fn trough<C: RangeBounds<f32>>(
         }
 
         // generate prominences
+        let prominence_step = ((PROMINENCE_STEP * scale).round() as usize).max(1);
         for (d, cell) in range.iter().enumerate() {
             let mut cur = cell;
-            if d % 6 != 0 {
+            if d % prominence_step != 0 {
                 continue;
             }
             for _ in 0..ridge_depth {
                 // Find the downhill cell.
                 let min = grid
                     .voronoi
-                    .cells[cur]
+                    .cell(*cur)
                     .adjacent_cells
                     .iter()
-                    .min_by(|a, b| grid.heights[a.as_usize()].cmp(&grid.heights[b.as_usize()]))
+                    .min_by(|a, b| {
+                        let (a, b) = (grid.heights[a.as_usize()], grid.heights[b.as_usize()]);
+                        a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+                    })
                     .unwrap();
-                grid.heights[min.as_usize()] =
-                    ((grid.heights[cur.as_usize()] as u32 * 2 + grid.heights[min.as_usize()] as u32) / 3) as u8;
+                grid.heights[min.as_usize()] = (grid.heights[cur.as_usize()] * 2.0 + grid.heights[min.as_usize()]) / 3.0;
                 cur = min;
             }
         }
     }
+    Ok(())
 }
 
 fn add(
     grid: &mut Grid,
     rng: &mut StdRng,
     range: HeightRange,
-    value: i16,
+    value: f32,
 ) {
     let min = range.min();
-    let max = range.max();
 
     for h in &mut grid.heights {
-        if *h >= min && *h <= max {
+        if range.contains(*h) {
             *h = if min == OCEAN_HEIGHT {
                 // TODO: decide if I want to keep this.
                 // Maybe add flag to saturate within the range?
-                (*h as i16 + value).min(WORLD_MAX as i16).max(OCEAN_HEIGHT as i16) as u8
+                (*h + value).min(WORLD_MAX).max(OCEAN_HEIGHT)
             } else {
-                (*h as i16 + value).min(WORLD_MAX as i16).max(0) as u8
+                (*h + value).min(WORLD_MAX).max(0.0)
             };
         }
     }
@@ -658,18 +917,17 @@ fn multiply(
     value: f32,
 ) {
     let min = range.min();
-    let max = range.max();
 
     for h in &mut grid.heights {
-        if *h >= min && *h <= max {
+        if range.contains(*h) {
             *h = if min == OCEAN_HEIGHT {
                 // TODO: decide if I want to keep this.
                 // Maybe add flag to saturate within the range?
-                ((*h - OCEAN_HEIGHT) as f32) * value + OCEAN_HEIGHT as f32
+                (*h - OCEAN_HEIGHT) * value + OCEAN_HEIGHT
             } else {
-                *h as f32 * value
-            }.min(WORLD_MAX as f32)
-                .max(0.0) as u8
+                *h * value
+            }.min(WORLD_MAX)
+                .max(0.0)
         }
     }
 }
@@ -682,20 +940,13 @@ fn strait<W: RangeBounds<f32>>(
     width: W,
     direction: Cartesianality,
 ) {
-    let mut width = match (width.start_bound(), width.end_bound()) {
-        (Bound::Included(s), Bound::Excluded(e)) => Uniform::new(s, e).sample(rng),
-        (Bound::Included(s), Bound::Included(e)) => Uniform::new_inclusive(s, e).sample(rng),
-        (Bound::Included(v), Bound::Unbounded)
-        | (Bound::Excluded(v), Bound::Unbounded)
-        | (Bound::Unbounded, Bound::Excluded(v))
-        | (Bound::Unbounded, Bound::Included(v)) => *v,
-        _ => unreachable!(),
-    }.min(grid.cells_x as f32 / 3.0);
+    let mut width = sample_range(rng, &width);
     if width < 1.0 && rng.gen::<f32>() < width {
         return;
     }
+    width = (width * cell_scale(grid)).min(grid.cells_x as f32 / 3.0);
 
-    let mut used = vec![false; grid.voronoi.cells.len()];
+    let mut used = vec![false; grid.voronoi.cell_count()];
     let (start_x, start_y, end_x, end_y) = if Cartesianality::Vertical == direction {
         let start_x = Uniform::new(
             grid.size.width as f32 * 0.3,
@@ -709,7 +960,7 @@ fn strait<W: RangeBounds<f32>>(
         ).sample(rng)
             .floor();
 
-        (start_x, 5.0, end_x, (grid.size.height - 5) as f32)
+        (start_x, 5.0, end_x, grid.size.height.saturating_sub(5) as f32)
     } else {
         let start_y = Uniform::new(
             grid.size.height as f32 * 0.3,
@@ -723,7 +974,7 @@ fn strait<W: RangeBounds<f32>>(
         ).sample(rng)
             .floor();
 
-        (5.0, start_y, (grid.size.width - 5) as f32, end_y)
+        (5.0, start_y, grid.size.width.saturating_sub(5) as f32, end_y)
     };
 
     let start = grid.coords_to_cell_index(start_x, start_y);
@@ -735,16 +986,14 @@ fn strait<W: RangeBounds<f32>>(
     while width > 0.0 {
         let exp = 0.9 - step * width;
         for r in range.iter() {
-            for a in grid.voronoi.cells[r].adjacent_cells.iter() {
+            for a in grid.voronoi.cell(*r).adjacent_cells.iter() {
                 if used[a.as_usize()] {
                     continue;
                 }
                 used[a.as_usize()] = true;
                 query.push(*a);
-                grid.heights[a.as_usize()] = (grid.heights[a.as_usize()] as f32).powf(exp) as u8;
-                if grid.heights[a.as_usize()] > WORLD_MAX {
-                    grid.heights[a.as_usize()] = 5;
-                }
+                let height = &mut grid.heights[a.as_usize()];
+                *height = height.powf(exp).min(WORLD_MAX);
             }
         }
 
@@ -758,10 +1007,10 @@ fn strait<W: RangeBounds<f32>>(
     fn get_range(
         grid: &Grid,
         rng: &mut StdRng,
-        cur: PointIndex,
-        end: PointIndex,
-    ) -> Vec<PointIndex> {
-        let cells = &grid.voronoi.cells;
+        cur: CellIndex,
+        end: CellIndex,
+    ) -> Vec<CellIndex> {
+        let voronoi = &grid.voronoi;
         let points = &grid.points;
         let mut cur = cur;
 
@@ -770,9 +1019,9 @@ fn strait<W: RangeBounds<f32>>(
 
         while cur != end {
             let mut min = std::f32::INFINITY;
-            for &cell in &cells[&cur].adjacent_cells {
-                let mut diff = (points[end].x - points[cell].x).powi(2)
-                    + (points[end].y - points[cell].y).powi(2);
+            for &cell in voronoi.cell(cur).adjacent_cells {
+                let (target, point) = (points[end.as_usize()], points[cell.as_usize()]);
+                let mut diff = grid.topology.distance_squared(target, point, grid.size);
                 if rng.gen::<f32>() > 0.8 {
                     diff = diff / 2.0;
                 }
@@ -789,61 +1038,80 @@ fn strait<W: RangeBounds<f32>>(
 }
 
 fn smooth(grid: &mut Grid, rng: &mut StdRng, force: u32) {
+    let force = force.max(1) as f32;
+
+    // A pass blurs the heights over about a cell, so a finer grid needs more
+    // passes to blur them as far. All but the last pass only average.
+    let scale = cell_scale(grid);
+    let passes = ((scale * scale).round() as u32).clamp(1, MAX_SMOOTH_PASSES);
+
+    let voronoi = &grid.voronoi;
     let heights = &mut grid.heights;
-    let cells = &grid.voronoi.cells;
 
-    for i in 0..heights.len() {
-        let h = heights[i];
-        let mut a = vec![h as u32];
-        for cell_index in cells[&i.into()].adjacent_cells.iter() {
-            a.push(heights[cell_index.as_usize()] as u32);
+    for pass in 1..=passes {
+        let force = if pass == passes { force } else { 1.0 };
+        for i in 0..heights.len() {
+            let h = heights[i];
+            let adjacent = voronoi.cell(i.into()).adjacent_cells;
+            let sum = adjacent.iter().map(|c| heights[c.as_usize()]).sum::<f32>() + h;
+            let mean = sum / (adjacent.len() + 1) as f32;
+            heights[i] = (h * (force - 1.0) + mean / force).min(WORLD_MAX);
         }
-        let height = h as u32 * (force - 1) + a.iter().sum::<u32>() / a.len() as u32 / force;
-        heights[i] = height.min(WORLD_MAX as u32) as u8
     }
 }
 
-fn get_blob_power(density: NonZeroU32) -> f32 {
-    match density.get() {
-        1 => 0.98,
-        2 => 0.985,
-        3 => 0.987,
-        4 => 0.9892,
-        5 => 0.9911,
-        6 => 0.9921,
-        7 => 0.9934,
-        8 => 0.9942,
-        9 => 0.9946,
-        10 => 0.995,
-        _ => unreachable!(),
+// Sample a value from `range`. A range with only one bound gives that bound
+// and an empty range gives its start.
+fn sample_range<R: RangeBounds<f32>>(rng: &mut StdRng, range: &R) -> f32 {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(&s), Bound::Excluded(&e)) if s < e => Uniform::new(s, e).sample(rng),
+        (Bound::Included(&s), Bound::Included(&e)) if s <= e => Uniform::new_inclusive(s, e).sample(rng),
+        (Bound::Included(&v), _)
+        | (Bound::Excluded(&v), _)
+        | (Bound::Unbounded, Bound::Excluded(&v))
+        | (Bound::Unbounded, Bound::Included(&v)) => v,
+        (Bound::Unbounded, Bound::Unbounded) => 0.0,
     }
 }
 
-fn get_line_power(density: NonZeroU32) -> f32 {
-    match density.get() {
-        1 => 0.81,
-        2 => 0.82,
-        3 => 0.83,
-        4 => 0.84,
-        5 => 0.855,
-        6 => 0.87,
-        7 => 0.885,
-        8 => 0.91,
-        9 => 0.92,
-        10 => 0.93,
-        _ => unreachable!(),
+// `Uniform::new` that samples `start` for an empty range instead of panicking,
+// e.g. for a template placing hills at exactly 60% of the width.
+fn uniform<X: SampleUniform + PartialOrd + Copy>(start: X, end: X) -> Uniform<X> {
+    if start < end {
+        Uniform::new(start, end)
+    } else {
+        Uniform::new_inclusive(start, start)
     }
 }
 
+// Cell count the primitives are tuned for.
+const REFERENCE_CELLS: f32 = 10_000.0;
+// Share of its height, in the exponent, a blob loses with every ring of cells
+// on the reference grid.
+const BLOB_DECAY: f32 = 0.02;
+// Same for ranges and troughs.
+const LINE_DECAY: f32 = 0.19;
+// Cells between the prominences along a range on the reference grid.
+const PROMINENCE_STEP: f32 = 6.0;
+// Most passes a smooth makes, however fine the grid.
+const MAX_SMOOTH_PASSES: u32 = 16;
+
+// How many times finer the grid is than the reference grid along either
+// axis. Blobs and lines spread over as many times more rings of cells to
+// cover the same part of the map.
+pub(crate) fn cell_scale(grid: &Grid) -> f32 {
+    (grid.voronoi.cell_count() as f32 / REFERENCE_CELLS).sqrt()
+}
+
 fn get_range(
     grid: &Grid,
     used: &mut [bool],
     rng: &mut StdRng,
-    cur: PointIndex,
-    end: PointIndex,
+    cur: CellIndex,
+    end: CellIndex,
     cmp_value: f32,
-) -> VecDeque<PointIndex> {
-    let cells = &grid.voronoi.cells;
+) -> VecDeque<CellIndex> {
+    let voronoi = &grid.voronoi;
     let points = &grid.points;
     let mut cur = cur;
 
@@ -853,12 +1121,12 @@ fn get_range(
 
     while cur != end {
         let mut min = std::f32::INFINITY;
-        for &cell in &cells[&cur].adjacent_cells {
+        for &cell in voronoi.cell(cur).adjacent_cells {
             if used[cell.as_usize()] {
                 continue;;
             }
-            let mut diff =
-                (points[end].x - points[cell].x).powi(2) + (points[end].y - points[cell].y).powi(2);
+            let (target, point) = (points[end.as_usize()], points[cell.as_usize()]);
+            let mut diff = grid.topology.distance_squared(target, point, grid.size);
             if rng.gen::<f32>() > cmp_value {
                 diff = diff / 2.0;
             }
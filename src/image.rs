@@ -0,0 +1,383 @@
+//! Grayscale images, read from and written to PNG or PGM files.
+//!
+//! Used to import heightmaps sketched in a paint program, see
+//! `HeightmapGenerator::generate_from_image`, and to export them to terrain
+//! tools, see `raster::heightmap_image`.
+
+use std::fmt;
+
+use triangulation::Point;
+
+use crate::Size;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// A file format for grayscale images.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Png,
+    /// Binary PGM, as read by most terrain tools that take raw heightfields.
+    Pgm,
+}
+
+impl ImageFormat {
+    /// The format a file name ending in `extension` is in, PNG unless it is
+    /// `pgm`.
+    pub fn from_extension(extension: &str) -> Self {
+        if extension.eq_ignore_ascii_case("pgm") {
+            ImageFormat::Pgm
+        } else {
+            ImageFormat::Png
+        }
+    }
+}
+
+/// A grayscale image with samples from 0 to `max_value`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GrayImage {
+    width: u32,
+    height: u32,
+    max_value: u16,
+    pixels: Vec<u16>,
+}
+
+impl GrayImage {
+    /// An image from its pixels, row by row from the top left.
+    pub fn new(width: u32, height: u32, max_value: u16, pixels: Vec<u16>) -> Result<Self, ImageError> {
+        if width == 0 || height == 0 {
+            return Err(ImageError::Invalid("the image is empty"));
+        }
+        if max_value == 0 {
+            return Err(ImageError::Invalid("the maximum value is 0"));
+        }
+        if pixels.len() != width as usize * height as usize {
+            return Err(ImageError::Invalid("the number of pixels does not match the image size"));
+        }
+        if pixels.iter().any(|&p| p > max_value) {
+            return Err(ImageError::Invalid("a pixel exceeds the maximum value"));
+        }
+        Ok(GrayImage {
+            width,
+            height,
+            max_value,
+            pixels,
+        })
+    }
+
+    /// Decode a PNG or PGM file, told apart by their signature. Color images
+    /// are converted to their luminance, alpha is ignored.
+    pub fn decode(data: &[u8]) -> Result<Self, ImageError> {
+        if data.starts_with(PNG_SIGNATURE) {
+            decode_png(data)
+        } else if data.starts_with(b"P2") || data.starts_with(b"P5") {
+            decode_pgm(data)
+        } else {
+            Err(ImageError::UnknownFormat)
+        }
+    }
+
+    /// Encode in `format`. PNG samples are scaled to the full range of 8 bits
+    /// if the maximum value fits in them, of 16 bits otherwise.
+    pub fn encode(&self, format: ImageFormat) -> Result<Vec<u8>, ImageError> {
+        match format {
+            ImageFormat::Png => self.encode_png(),
+            ImageFormat::Pgm => Ok(self.encode_pgm()),
+        }
+    }
+
+    fn encode_png(&self) -> Result<Vec<u8>, ImageError> {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, self.width, self.height);
+        encoder.set_color(png::ColorType::Grayscale);
+        let samples = if self.max_value <= u8::max_value() as u16 {
+            encoder.set_depth(png::BitDepth::Eight);
+            let max_value = self.max_value as f32;
+            self.pixels
+                .iter()
+                .map(|&p| (p as f32 / max_value * 255.0).round() as u8)
+                .collect()
+        } else {
+            encoder.set_depth(png::BitDepth::Sixteen);
+            let max_value = self.max_value as f32;
+            self.pixels
+                .iter()
+                .flat_map(|&p| ((p as f32 / max_value * 65535.0).round() as u16).to_be_bytes().to_vec())
+                .collect::<Vec<_>>()
+        };
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&samples)?;
+        drop(writer);
+        Ok(data)
+    }
+
+    fn encode_pgm(&self) -> Vec<u8> {
+        let mut data = format!("P5\n{} {}\n{}\n", self.width, self.height, self.max_value).into_bytes();
+        if self.max_value > u8::max_value() as u16 {
+            data.extend(self.pixels.iter().flat_map(|p| p.to_be_bytes().to_vec()));
+        } else {
+            data.extend(self.pixels.iter().map(|&p| p as u8));
+        }
+        data
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn max_value(&self) -> u16 {
+        self.max_value
+    }
+
+    pub fn pixels(&self) -> &[u16] {
+        &self.pixels
+    }
+
+    /// Luminance from 0 to 1 at position `p` on a map of `size`, with the
+    /// image stretched over the map. Interpolated between the four nearest
+    /// pixels.
+    pub fn luminance(&self, p: Point, size: Size) -> f32 {
+        // Pixel centers are at half coordinates.
+        let to_pixel = |v: f32, map_length: u32, length: u32| {
+            (v / map_length as f32 * length as f32 - 0.5).max(0.0).min((length - 1) as f32)
+        };
+        let x = to_pixel(p.x, size.width, self.width);
+        let y = to_pixel(p.y, size.height, self.height);
+        let (x0, y0) = (x as u32, y as u32);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let pixel = |x: u32, y: u32| self.pixels[(y * self.width + x) as usize] as f32;
+        let top = pixel(x0, y0) * (1.0 - fx) + pixel(x1, y0) * fx;
+        let bottom = pixel(x0, y1) * (1.0 - fx) + pixel(x1, y1) * fx;
+        (top * (1.0 - fy) + bottom * fy) / self.max_value as f32
+    }
+}
+
+fn decode_png(data: &[u8]) -> Result<GrayImage, ImageError> {
+    let mut decoder = png::Decoder::new(data);
+    // Palettes and bit depths below 8 are expanded, 16 bits are kept.
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let (info, mut reader) = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut buffer)?;
+
+    let (color_type, bit_depth) = reader.output_color_type();
+    let (samples, max_value): (Vec<_>, _) = match bit_depth {
+        png::BitDepth::Sixteen => (
+            buffer.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect(),
+            u16::max_value(),
+        ),
+        _ => (buffer.iter().map(|&b| b as u16).collect(), u8::max_value() as u16),
+    };
+    let pixels = samples
+        .chunks_exact(color_type.samples())
+        .map(|s| match color_type {
+            png::ColorType::RGB | png::ColorType::RGBA =>
+                (0.299 * s[0] as f32 + 0.587 * s[1] as f32 + 0.114 * s[2] as f32).round() as u16,
+            _ => s[0],
+        })
+        .collect();
+
+    GrayImage::new(info.width, info.height, max_value, pixels)
+}
+
+fn decode_pgm(data: &[u8]) -> Result<GrayImage, ImageError> {
+    let binary = data[1] == b'5';
+    let mut pos = 2;
+    let width = read_pgm_number(data, &mut pos)?;
+    let height = read_pgm_number(data, &mut pos)?;
+    let max_value = read_pgm_number(data, &mut pos)?;
+    if max_value > u16::max_value() as u32 {
+        return Err(ImageError::Invalid("the maximum value is above 65535"));
+    }
+
+    let count = width as usize * height as usize;
+    let pixels = if binary {
+        // A single whitespace character separates the header from the pixels,
+        // which take two bytes each if the maximum value needs them.
+        let start = pos + 1;
+        let bytes = if max_value > u8::max_value() as u32 { 2 } else { 1 };
+        let raster = count
+            .checked_mul(bytes)
+            .and_then(|length| data.get(start..start.checked_add(length)?))
+            .ok_or(ImageError::Invalid("the pixels are cut off"))?;
+        if bytes == 2 {
+            raster.chunks_exact(2).map(|b| u16::from_be_bytes([b[0], b[1]])).collect()
+        } else {
+            raster.iter().map(|&b| b as u16).collect()
+        }
+    } else {
+        (0..count)
+            .map(|_| read_pgm_number(data, &mut pos).map(|v| v.min(u16::max_value() as u32) as u16))
+            .collect::<Result<_, _>>()?
+    };
+
+    GrayImage::new(width, height, max_value as u16, pixels)
+}
+
+// Skip whitespace and comments and read the decimal number after them.
+fn read_pgm_number(data: &[u8], pos: &mut usize) -> Result<u32, ImageError> {
+    loop {
+        match data.get(*pos) {
+            Some(b'#') => {
+                while data.get(*pos).map_or(false, |&b| b != b'\n') {
+                    *pos += 1;
+                }
+            }
+            Some(b) if b.is_ascii_whitespace() => *pos += 1,
+            _ => break,
+        }
+    }
+    let start = *pos;
+    while data.get(*pos).map_or(false, u8::is_ascii_digit) {
+        *pos += 1;
+    }
+    std::str::from_utf8(&data[start..*pos])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ImageError::Invalid("expected a number in the PGM data"))
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// Neither a PNG nor a PGM file.
+    UnknownFormat,
+    Png(png::DecodingError),
+    PngEncoding(png::EncodingError),
+    /// The image is malformed, for the given reason.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::UnknownFormat => write!(f, "not a PNG or PGM image"),
+            ImageError::Png(e) => write!(f, "invalid PNG image: {}", e),
+            ImageError::PngEncoding(e) => write!(f, "could not encode PNG image: {}", e),
+            ImageError::Invalid(reason) => write!(f, "invalid image: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Png(e) => Some(e),
+            ImageError::PngEncoding(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<png::DecodingError> for ImageError {
+    fn from(e: png::DecodingError) -> Self {
+        ImageError::Png(e)
+    }
+}
+
+impl From<png::EncodingError> for ImageError {
+    fn from(e: png::EncodingError) -> Self {
+        ImageError::PngEncoding(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(max_value: u16) -> GrayImage {
+        let pixels = (0..12).map(|i| (i as u32 * max_value as u32 / 11) as u16).collect();
+        GrayImage::new(4, 3, max_value, pixels).unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_both_formats() {
+        for &max_value in &[255, 65535] {
+            let image = gradient(max_value);
+            for &format in &[ImageFormat::Png, ImageFormat::Pgm] {
+                let data = image.encode(format).unwrap();
+                assert_eq!(GrayImage::decode(&data).unwrap(), image, "{:?} of {}", format, max_value);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_the_maximum_value_of_pgm() {
+        let image = gradient(1000);
+        let data = image.encode(ImageFormat::Pgm).unwrap();
+        assert!(data.starts_with(b"P5\n4 3\n1000\n"));
+        assert_eq!(GrayImage::decode(&data).unwrap(), image);
+
+        // PNG scales to 16 bits.
+        let decoded = GrayImage::decode(&image.encode(ImageFormat::Png).unwrap()).unwrap();
+        assert_eq!(decoded.max_value(), 65535);
+        assert_eq!(decoded.pixels()[11], 65535);
+    }
+
+    #[test]
+    fn decodes_plain_pgm_with_comments() {
+        let image = GrayImage::decode(b"P2\n# a comment\n2 2 # another\n10\n0 5\n10 7\n").unwrap();
+        assert_eq!(image, GrayImage::new(2, 2, 10, vec![0, 5, 10, 7]).unwrap());
+    }
+
+    #[test]
+    fn converts_color_png_to_luminance() {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 1);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[255, 255, 255, 255, 0, 0]).unwrap();
+        drop(writer);
+
+        let image = GrayImage::decode(&data).unwrap();
+        assert_eq!(image.pixels(), &[255, 76]);
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        let invalid: &[&[u8]] = &[
+            b"P5\n4 3\n255\n\x00\x01",
+            b"P5\n4294967295 4294967295\n65535\n\x00",
+            b"P2\n2 2\n10\n0 5 10",
+            b"P2\n2 2\n10\n0 5 10 11",
+            b"P5\n2 2\n70000\n",
+            b"P5\n0 2\n255\n",
+            b"P5\nwide",
+        ];
+        for data in invalid {
+            match GrayImage::decode(data) {
+                Err(ImageError::Invalid(_)) => {}
+                other => panic!("expected {:?} to be invalid, got {:?}", String::from_utf8_lossy(data), other),
+            }
+        }
+        match GrayImage::decode(b"GIF89a") {
+            Err(ImageError::UnknownFormat) => {}
+            other => panic!("expected an unknown format, got {:?}", other),
+        }
+        match GrayImage::decode(PNG_SIGNATURE) {
+            Err(ImageError::Png(_)) => {}
+            other => panic!("expected a PNG error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interpolates_luminance() {
+        let image = GrayImage::new(2, 1, 100, vec![0, 100]).unwrap();
+        let size = Size::new(200, 50);
+        assert_eq!(image.luminance(Point::new(0.0, 25.0), size), 0.0);
+        assert_eq!(image.luminance(Point::new(100.0, 25.0), size), 0.5);
+        assert_eq!(image.luminance(Point::new(200.0, 25.0), size), 1.0);
+    }
+
+    #[test]
+    fn picks_format_by_extension() {
+        assert_eq!(ImageFormat::from_extension("PGM"), ImageFormat::Pgm);
+        assert_eq!(ImageFormat::from_extension("png"), ImageFormat::Png);
+        assert_eq!(ImageFormat::from_extension("jpg"), ImageFormat::Png);
+    }
+}
@@ -1,32 +1,64 @@
 #![allow(unused_variables)]
 
-mod heightmap;
+pub mod azgaar;
+pub mod erosion;
+mod error;
+mod geometry;
+pub mod heightmap;
+pub mod image;
+pub mod locator;
+pub mod mask;
+mod options;
+pub mod pack;
+mod permalink;
+pub mod pipeline;
+pub mod points;
+pub mod progress;
+pub mod raster;
+pub mod rng;
+mod save;
+pub mod topology;
 mod util;
-mod voronoi;
+pub mod voronoi;
 mod svg_test;
 
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
 use std::iter::successors;
+use std::path::Path;
 #[cfg(target_arch = "wasm32")]
 use std::panic;
-use std::num::NonZeroU32;
 
 #[cfg(target_arch = "wasm32")]
 use js_sys::{Array, JsString};
-use rand::{random, SeedableRng};
+use rand::random;
 use rand::distributions::Distribution;
 use rand::distributions::uniform::Uniform;
 use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
 use svg::node::Value;
 use svg::node::element::path::Data;
-use triangulation::{Delaunay, EdgeIndex, Point, PointIndex};
+use triangulation::Point;
 use wasm_bindgen::prelude::*;
 
-use heightmap::{HeightmapGenerator, OCEAN_HEIGHT, Template, WORLD_MAX};
+pub use error::MapError;
+use heightmap::{OCEAN_HEIGHT, Template};
+pub use options::{MapOptions, OptionsError};
+pub use permalink::{Permalink, PermalinkError};
+pub use pipeline::{Pipeline, Stage};
+pub use points::PointDistribution;
+pub use progress::{CancelToken, Progress};
+pub use save::{FORMAT_VERSION, SaveError};
+pub use topology::Topology;
 use util::FloatExt;
-use voronoi::Voronoi;
+use locator::PointLocator;
+use pack::PackGrid;
+use progress::NoProgress;
+use voronoi::{CellIndex, VertexIndex, Voronoi};
 #[cfg(not(target_arch = "wasm32"))]
 use svg_test::*;
+#[cfg(not(target_arch = "wasm32"))]
+pub use svg_test::{CELLS_FILE, COASTLINE_FILE, HEIGHTMAP_FILE};
 
 #[wasm_bindgen(module = "/modules/ui-util.js")]
 #[cfg(target_arch = "wasm32")]
@@ -57,7 +89,7 @@ extern "C" {
     fn __draw_heightmap(
         height_paths: Array,
         height_colors: Array,
-        height_values: &[u8],
+        height_values: &[f32],
     );
     #[wasm_bindgen(js_name = clearHeightmap)]
     fn clear_heightmap();
@@ -91,8 +123,7 @@ macro_rules! time_start {
         if cfg!(target_arch = "wasm32") {
             web_sys::console::time_with_label($e);
         } else {
-            // TODO: track start time
-            eprintln!("time_start: {}", $e);
+            $crate::progress::timer_start($e);
         }
     }
 }
@@ -103,7 +134,7 @@ macro_rules! time_end {
         if cfg!(target_arch = "wasm32") {
             web_sys::console::time_end_with_label($e);
         } else {
-            eprintln!("time_end: {}", $e);
+            $crate::progress::timer_end($e);
         }
     }
 }
@@ -123,23 +154,32 @@ pub fn run() -> Result<(), JsValue> {
     // modules
     // notes
     let customization = MapCustomization::None;
-    let winds = [225, 45, 225, 315, 135, 315]; // default wind directions
     // TODO: biomes
     // TODO: name bases
 
     // TODO: Load stored options from local storage
-    // TODO: get graph/svg size from input field
-    let graph_size = Size::new(1000, 1000);
+    let options = MapOptions::default();
 
     // TODO: setup landmass/ocean bases
 
     remove_loading();
 
-    load_initial_map(graph_size);
+    load_initial_map(options).map_err(|e| JsValue::from_str(&e.to_string()))?;
 
     Ok(())
 }
 
+/// Generate and draw a new map from options given as JSON, as produced by
+/// `MapOptions::to_json`. Missing options use their defaults.
+#[wasm_bindgen(js_name = generateMap)]
+pub fn generate_map(options: &str) -> Result<(), JsValue> {
+    let options = MapOptions::from_json(options).map_err(|e| JsValue::from_str(&e.to_string()))?;
+    undraw_all();
+    Map::generate(options)
+        .and_then(|map| map.draw())
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
 pub enum MapCustomization {
     None = 0,
     HightmapDraw = 1,
@@ -148,7 +188,7 @@ pub enum MapCustomization {
     CulturesDraw = 4,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
 pub struct Size {
     pub width: u32,
     pub height: u32,
@@ -163,48 +203,107 @@ impl Size {
     }
 }
 
-fn load_initial_map(graph_size: Size) -> Map {
+impl std::fmt::Display for Size {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseSizeError(String);
+
+impl std::fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid map size \"{}\", expected WIDTHxHEIGHT", self.0)
+    }
+}
+
+impl std::error::Error for ParseSizeError {}
+
+impl std::str::FromStr for Size {
+    type Err = ParseSizeError;
+
+    /// Parse a size written as `WIDTHxHEIGHT`, e.g. `1000x1000`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseSizeError(s.to_owned());
+        let mut dims = s.splitn(2, |c| c == 'x' || c == 'X');
+        let width = dims.next().ok_or_else(err)?.trim().parse().map_err(|_| err())?;
+        let height = dims.next().ok_or_else(err)?.trim().parse().map_err(|_| err())?;
+        if width == 0 || height == 0 {
+            return Err(err());
+        }
+        Ok(Size::new(width, height))
+    }
+}
+
+fn load_initial_map(options: MapOptions) -> Result<Map, MapError> {
     // TODO: if valid link in href, load the map
-    // TODO: if there is a seed in the href, use seed
     // TODO: if map was saved and "load saved map" option checked, load from
     //       storage
-    // TODO: else, generate a new map
-    let density = NonZeroU32::new(1).unwrap();
-    generate_map_on_load(graph_size, density)
+
+    // If there is a seed in the href, use it along with the options there.
+    let link = current_href().and_then(|href| match Permalink::parse(&href) {
+        Ok(link) => Some(link),
+        Err(PermalinkError::MissingSeed) => None,
+        Err(e) => {
+            err!("{}", e);
+            None
+        }
+    });
+    match link {
+        Some(link) => generate_map_on_load(link.options, Some(link.seed)),
+        None => generate_map_on_load(options, None),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn current_href() -> Option<String> {
+    web_sys::window()?.location().href().ok()
 }
 
-fn generate_map_on_load(graph_size: Size, density: NonZeroU32) -> Map {
+#[cfg(not(target_arch = "wasm32"))]
+fn current_href() -> Option<String> {
+    None
+}
+
+fn generate_map_on_load(options: MapOptions, seed: Option<u64>) -> Result<Map, MapError> {
     // TODO: apply the default style, maybe do before
-    // TODO: generate map
-    let map = Map::generate(graph_size, density);
+    let map = match seed {
+        Some(seed) => Map::generate_with_seed(options, seed)?,
+        None => Map::generate(options)?,
+    };
+    map.draw()?;
     // TODO: focus on the current target, may have been set by href
     // TODO: apply the current (set in local storage) layer preset
-    map
+    Ok(map)
 }
 
-const DENSITY_STEP: u32 = 10_000;
+/// Fewest cells a grid can be asked for.
+pub const MIN_CELLS: u32 = 1_000;
+/// Most cells a grid can be asked for.
+pub const MAX_CELLS: u32 = 1_000_000;
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum Coast {
     None,
     Beach,
     Shallows,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum FeatureType {
     Island(IslandGroup),
     Ocean,
     Lake(LakeGroup),
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum LakeGroup {
     Freshwater,
     Salt,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
 pub enum IslandGroup {
     Continent,
     Island,
@@ -224,24 +323,29 @@ impl std::fmt::Display for FeatureType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Feature {
-    index: usize,
-    land: bool,
-    border: bool,
-    ty: FeatureType,
+    pub index: usize,
+    pub land: bool,
+    pub border: bool,
+    pub ty: FeatureType,
 }
 
 pub struct Grid {
     pub size: Size,
-    pub density: NonZeroU32,
+    /// Number of cells asked for. The grid has about as many.
+    pub cells_desired: u32,
     pub point_spacing: f32,
     pub cells_x: u32,
     pub cells_y: u32,
+    pub distribution: PointDistribution,
+    pub topology: Topology,
+    /// Points fencing in a flat grid, empty on a sphere.
     pub boundary: Vec<Point>,
     pub points: Vec<Point>,
     pub voronoi: Voronoi,
-    pub heights: Vec<u8>,
+    pub locator: PointLocator,
+    pub heights: Vec<f32>,
     // TODO: FeatureIndex?
     pub feature_map: Vec<Option<usize>>,
     pub features: Vec<Feature>,
@@ -249,53 +353,122 @@ pub struct Grid {
 }
 
 impl Grid {
-    pub fn new(size: Size, density: NonZeroU32, rng: &mut StdRng) -> Self {
+    pub fn new(
+        size: Size,
+        cells_desired: u32,
+        distribution: PointDistribution,
+        topology: Topology,
+        rng: &mut StdRng,
+        cancel: &CancelToken,
+    ) -> Result<Self, MapError> {
+        if cells_desired < MIN_CELLS || cells_desired > MAX_CELLS {
+            return Err(MapError::CellCountOutOfRange(cells_desired));
+        }
+        let area = size.width as u64 * size.height as u64;
+        if area < cells_desired as u64 {
+            return Err(MapError::InvalidSize(size));
+        }
+
         time_start!("place_points");
-        let cells_desired = DENSITY_STEP * density.get();
         // Spacing between points before jittering
         let spacing =
-            ((size.width * size.height / cells_desired) as f32)
+            ((area / cells_desired as u64) as f32)
             .sqrt()
             .round_decimals(2);
 
-        let cells_x = ((size.width as f32 + 0.5 * spacing) / spacing).floor() as u32;
-        let cells_y =  ((size.height as f32 + 0.5 * spacing) / spacing).floor() as u32;
+        if topology == Topology::Sphere {
+            let points = topology::fibonacci_sphere(cells_desired as usize, rng)
+                .into_iter()
+                .map(|p| topology::to_map(p, size))
+                .collect();
+            time_end!("place_points");
+            return Grid::from_points(size, cells_desired, distribution, topology, spacing, Vec::new(), points);
+        }
+
         // grid boundary points
-        let boundary = Grid::generate_boundary_points(size, spacing);
-        // jittered square grid
-        let points = Grid::generate_jittered_grid(size, spacing, rng);
+        let boundary = if topology == Topology::Cylinder {
+            Grid::generate_wrapped_boundary_points(size, spacing)
+        } else {
+            Grid::generate_boundary_points(size, spacing)
+        };
+        let points = match distribution {
+            PointDistribution::Jittered => Grid::generate_jittered_grid(size, spacing, rng),
+            PointDistribution::PoissonDisc => points::poisson_disc(size, spacing, rng),
+            PointDistribution::Lloyd(iterations) => {
+                let points = Grid::generate_jittered_grid(size, spacing, rng);
+                points::lloyd_relax(size, topology, &boundary, points, iterations, cancel)?
+            }
+        };
         time_end!("place_points");
 
-        time_start!("calculate_delaunay");
-        let mut allpoints = Vec::with_capacity(points.len() + boundary.len());
-        allpoints.extend_from_slice(&points);
-        allpoints.extend_from_slice(&boundary);
-        let mut delaunay = Delaunay::new(allpoints.as_slice()).unwrap();
-        delaunay.dcel.init_revmap();
-        time_end!("calculate_delaunay");
+        Grid::from_points(size, cells_desired, distribution, topology, spacing, boundary, points)
+    }
+
+    /// Build the grid graph over already placed points, e.g. ones loaded from
+    /// a saved map. All layers start out empty.
+    pub fn from_points(
+        size: Size,
+        cells_desired: u32,
+        distribution: PointDistribution,
+        topology: Topology,
+        spacing: f32,
+        boundary: Vec<Point>,
+        points: Vec<Point>,
+    ) -> Result<Self, MapError> {
+        let cells_x = ((size.width as f32 + 0.5 * spacing) / spacing).floor() as u32;
+        let cells_y =  ((size.height as f32 + 0.5 * spacing) / spacing).floor() as u32;
+        if cells_x == 0 || cells_y == 0 {
+            return Err(MapError::InvalidSize(size));
+        }
 
         time_start!("calculate_voronoi");
-        let voronoi = Voronoi::from_delaunay(&delaunay, &allpoints, points.len());
+        let voronoi = topology.voronoi(size, &points, &boundary)?;
         time_end!("calculate_voronoi");
 
-        let heights = vec![0; voronoi.cells.len()];
-        let feature_map = vec![None; voronoi.cells.len()];
+        let locator = PointLocator::new(size, spacing, &points);
+
+        let heights = vec![0.0; voronoi.cell_count()];
+        let feature_map = vec![None; voronoi.cell_count()];
         let features = vec![];
-        let coasts = vec![Coast::None; voronoi.cells.len()];
+        let coasts = vec![Coast::None; voronoi.cell_count()];
 
-        Grid {
+        Ok(Grid {
             size,
-            density,
+            cells_desired,
             point_spacing: spacing,
             cells_x,
             cells_y,
+            distribution,
+            topology,
             boundary,
             points,
             voronoi,
+            locator,
             heights,
             feature_map,
             features,
             coasts,
+        })
+    }
+
+    /// A grid without any points, to be filled in by the grid stage.
+    pub fn empty(size: Size, cells_desired: u32) -> Self {
+        Grid {
+            size,
+            cells_desired,
+            point_spacing: 0.0,
+            cells_x: 0,
+            cells_y: 0,
+            distribution: PointDistribution::default(),
+            topology: Topology::default(),
+            boundary: Vec::new(),
+            points: Vec::new(),
+            voronoi: Voronoi::default(),
+            locator: PointLocator::default(),
+            heights: Vec::new(),
+            feature_map: Vec::new(),
+            features: Vec::new(),
+            coasts: Vec::new(),
         }
     }
 
@@ -321,6 +494,22 @@ impl Grid {
         boundary
     }
 
+    /// Boundary points for a cylinder, above and below the map only. They are
+    /// spaced to repeat evenly across the seam.
+    fn generate_wrapped_boundary_points(size: Size, spacing: f32) -> Vec<Point> {
+        let offset = (spacing * -1.0).round();
+        let width = size.width as f32;
+        let count = (width / (spacing * 2.0)).round().max(1.0);
+
+        let mut boundary = Vec::new();
+        for i in successors(Some(0.5), |v| Some(v + 1.0)).take_while(|v| *v < count) {
+            let x = width * i / count;
+            boundary.push(Point::new(x, offset));
+            boundary.push(Point::new(x, size.height as f32 - offset));
+        }
+        boundary
+    }
+
     fn generate_jittered_grid(size: Size, spacing: f32, rng: &mut StdRng) -> Vec<Point> {
         // Square radius
         let radius = spacing / 2.0;
@@ -333,8 +522,8 @@ impl Grid {
         let height = size.height as f32;
 
         let mut points = Vec::new();
-        for y in successors(Some(radius), |v| Some(v + spacing)).take_while(|v| *v < width) {
-            for x in successors(Some(radius), |v| Some(v + spacing)).take_while(|v| *v < height) {
+        for y in successors(Some(radius), |v| Some(v + spacing)).take_while(|v| *v < height) {
+            for x in successors(Some(radius), |v| Some(v + spacing)).take_while(|v| *v < width) {
                 let xj = (x + jitter()).round_decimals(2).min(width);
                 let yj = (y + jitter()).round_decimals(2).min(height);
                 points.push(Point::new(xj, yj));
@@ -343,33 +532,34 @@ impl Grid {
         points
     }
 
-    // Return cell index on a regular square grid.
-    pub fn coords_to_cell_index(&self, x: f32, y: f32) -> PointIndex {
-        ((
-            ((x / self.point_spacing) as u32).min(self.cells_x - 1)
-                + ((y / self.point_spacing) as u32).min(self.cells_y - 1) * self.cells_x
-        ) as usize).into()
+    /// The cell whose polygon contains `(x, y)`, or the nearest cell for
+    /// positions off the map. An empty grid gives cell 0.
+    pub fn coords_to_cell_index(&self, x: f32, y: f32) -> CellIndex {
+        let nearest = self.locator.nearest(x, y).unwrap_or_default();
+        self.topology.refine_nearest(&self.voronoi, &self.points, self.size, nearest, x, y)
     }
 
-    pub fn mark_features(&mut self, rng: &mut StdRng, seed: u64) {
+    /// Group the cells into features and mark the coasts. Stops with
+    /// `MapError::Cancelled` between features once `cancel` is cancelled.
+    pub fn mark_features(&mut self, cancel: &CancelToken) -> Result<(), MapError> {
         time_start!("mark_features");
 
-        *rng = StdRng::seed_from_u64(seed);
-        self.coasts = vec![Coast::None; self.voronoi.cells.len()];
-        self.feature_map = vec![None; self.voronoi.cells.len()];
+        self.coasts = vec![Coast::None; self.voronoi.cell_count()];
+        self.feature_map = vec![None; self.voronoi.cell_count()];
         self.features.clear();
 
         let mut i = 0;
         let mut queue = VecDeque::new();
         queue.push_back(0);
         loop {
+            cancel.check()?;
             self.feature_map[queue[0]] = Some(i);
             let land = self.heights[queue[0]] >= OCEAN_HEIGHT;
             let mut border = false;
 
             while !queue.is_empty() {
                 let q = queue.pop_front().unwrap();
-                let cell = &self.voronoi.cells[&q.into()];
+                let cell = self.voronoi.cell(q.into());
                 if cell.border_cell {
                     border = true;
                 }
@@ -407,20 +597,49 @@ impl Grid {
             }
         }
 
+        // A sphere has no border to tell the ocean from lakes, so the largest
+        // body of water is the ocean.
+        if self.topology == Topology::Sphere {
+            let mut sizes = vec![0; self.features.len()];
+            for f in self.feature_map.iter().flatten() {
+                sizes[*f] += 1;
+            }
+            let ocean = self.features
+                .iter()
+                .filter(|f| !f.land)
+                .max_by_key(|f| sizes[f.index])
+                .map(|f| f.index);
+            if let Some(ocean) = ocean {
+                self.features[ocean].ty = FeatureType::Ocean;
+            }
+        }
+
         time_end!("mark_features");
+        Ok(())
     }
 }
 
-struct Map {
-    seed: u64,
-    rng: StdRng,
-    grid: Grid,
+pub struct Map {
+    pub seed: u64,
+    pub options: MapOptions,
+    /// Number of times each RNG stream was rerolled, by label.
+    pub rerolls: BTreeMap<String, u32>,
+    /// The heightmap template the map was generated from, picked at random
+    /// unless given in the options. None until the heightmap is generated, or
+    /// if unknown.
+    pub template: Option<Template>,
+    pub grid: Grid,
+    /// The finer graph over the land, built from `grid` once its features
+    /// are marked.
+    pub pack: PackGrid,
+    /// Checked between stages and in long loops, cancel it to abort.
+    pub cancel: CancelToken,
 }
 
 impl Map {
-    fn generate(graph_size: Size, density: NonZeroU32) -> Self {
+    pub fn generate(options: MapOptions) -> Result<Self, MapError> {
         let seed = random();
-        Self::generate_with_seed(graph_size, density, seed)
+        Self::generate_with_seed(options, seed)
     }
 
     // TODO: stuff to happen before this function
@@ -428,66 +647,97 @@ impl Map {
     // generate a new seed if needed
     // update the map size
     // randomizing the options
-    fn generate_with_seed(graph_size: Size, density: NonZeroU32, seed: u64) -> Self {
-        let mut rng = StdRng::seed_from_u64(seed);
-
-        let mut grid = Grid::new(graph_size, density, &mut rng);
-
-        time_start!("generate_hightmap");
-        HeightmapGenerator::generate_with_template(&mut grid, &mut rng, Template::Isthmus);
-        time_end!("generate_hightmap");
-
-        grid.mark_features(&mut rng, seed);
-        // TODO: open near sea lakes
-
-        draw_coastline(
-            &grid.voronoi,
-            &grid.heights,
-            &grid.feature_map,
-            &grid.features,
-            &grid.coasts,
-        );
-        draw_heightmap(&grid);
-        draw_cells(&grid);
-
-        // TODO: calculate map coords
-        // TODO: calculate temperatures
-        // TODO: generate precipitation
-        // TODO: pack voronoi graph?
-        // TODO: draw coastline
-
-        // TODO: elevate lakes
-        // TODO: generate rivers
-        // TODO: define biomes
-
-        // TODO: rank cells?
-        // TODO: populate cultures
-        // TODO: expand cultures
-        // TODO: generate burgs and states
-        // TODO: generate religions
-
-        // TODO: draw states
-        // TODO: draw borders
-        // TODO: draw state labels
-        // TODO: add zone?
-        // TODO: add markers
+    pub fn generate_with_seed(options: MapOptions, seed: u64) -> Result<Self, MapError> {
+        Self::generate_with_progress(options, seed, &mut NoProgress, CancelToken::new())
+    }
 
+    /// Like `generate_with_seed`, reporting each finished stage to `progress`
+    /// and stopping with `MapError::Cancelled` once `cancel` is cancelled.
+    pub fn generate_with_progress(
+        options: MapOptions,
+        seed: u64,
+        progress: &mut dyn Progress,
+        cancel: CancelToken,
+    ) -> Result<Self, MapError> {
+        let mut map = Map::new(options, seed);
+        map.cancel = cancel;
+        Pipeline::default().run_on(&mut map, progress)?;
         // TODO: print stats
+        Ok(map)
+    }
 
+    /// A map with an empty grid, ready to be filled in by a `Pipeline`.
+    pub fn new(options: MapOptions, seed: u64) -> Self {
+        let grid = Grid::empty(options.size, options.cells);
+        let pack = PackGrid::empty(options.size);
         Map {
             seed,
-            rng,
+            options,
+            rerolls: BTreeMap::new(),
+            template: None,
             grid,
+            pack,
+            cancel: CancelToken::new(),
         }
     }
+
+    /// The RNG stream labelled `label`, e.g. `rng::HEIGHTMAP`.
+    ///
+    /// Every call returns the stream from its start, so a stage re-run with
+    /// the same seed and options produces the same result.
+    pub fn rng(&self, label: &str) -> StdRng {
+        let reroll = self.rerolls.get(label).copied().unwrap_or(0);
+        rng::stage_rng(self.seed, label, reroll)
+    }
+
+    /// Switch the stream labelled `label` to a fresh one, leaving all other
+    /// streams untouched. Re-run the stages using it to get a new result.
+    pub fn reroll(&mut self, label: &str) {
+        *self.rerolls.entry(label.to_owned()).or_insert(0) += 1;
+    }
+
+    /// Real-world elevation of `cell` of the grid in meters, negative below
+    /// sea level. See `heightmap::elevation`.
+    pub fn elevation(&self, cell: CellIndex) -> f32 {
+        heightmap::elevation(self.grid.heights[cell.as_usize()], self.options.height_exponent)
+    }
     // TODO: stuff to happen after function
     // draw the scale bar
     // TODO: draw ocean layers
+
+    /// Draw the generated layers.
+    ///
+    /// Generation does not touch the DOM, so this is only needed when the map
+    /// should be displayed. On wasm the layers are handed to the page, natively
+    /// they are written out as SVG files to the current directory.
+    pub fn draw(&self) -> Result<(), MapError> {
+        self.draw_to(Path::new(""))
+    }
+
+    /// Like `draw`, but natively the SVG files are written to `dir`.
+    pub fn draw_to(&self, dir: &Path) -> Result<(), MapError> {
+        draw_coastline(
+            dir,
+            &self.grid.voronoi,
+            &self.grid.heights,
+            &self.grid.feature_map,
+            &self.grid.features,
+            &self.grid.coasts,
+            &self.cancel,
+        )?;
+        draw_heightmap(dir, &self.grid, &self.cancel)?;
+        draw_cells(dir, &self.grid)?;
+        // TODO: draw states
+        // TODO: draw borders
+        // TODO: draw state labels
+        Ok(())
+    }
 }
 
-fn draw_cells(grid: &Grid) {
+fn draw_cells(dir: &Path, grid: &Grid) -> Result<(), MapError> {
     let mut data = Data::new();
-    for mut vertices in grid.voronoi.get_cell_vertex_coords() {
+    for vertices in grid.voronoi.get_clipped_cell_vertex_coords() {
+        let mut vertices = vertices.into_iter();
         if let Some(start) = vertices.next() {
             data = data.move_to((start.x, start.y));
             for vertex in vertices {
@@ -499,16 +749,24 @@ fn draw_cells(grid: &Grid) {
 
     let data: Value = data.into();
 
-    __draw_cells(data.to_string());
+    _draw_cells(dir, data.to_string())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn _draw_cells(dir: &Path, path: String) -> Result<(), MapError> {
+    __draw_cells(path);
+    Ok(())
 }
 
 fn draw_coastline(
+    dir: &Path,
     voronoi: &Voronoi,
-    heights: &[u8],
+    heights: &[f32],
     feature_map: &[Option<usize>],
     features: &[Feature],
     coasts: &[Coast],
-) {
+    cancel: &CancelToken,
+) -> Result<(), MapError> {
     time_start!("draw_coastline");
 
     let mut used = vec![false; features.len()];
@@ -521,13 +779,13 @@ fn draw_coastline(
     let mut coastline_ids = Vec::new();
 
     let find_start = |i: usize, ty: Coast| {
-        let cell = &voronoi.cells[&i.into()];
+        let cell = voronoi.cell(i.into());
         if ty == Coast::Shallows && cell.border_cell {
             // Use a border cell.
             cell.vertices
                 .iter()
-                .find(|v|
-                    voronoi.vertices[v]
+                .find(|&&v|
+                    voronoi.vertex(v)
                         .connected_cells
                         .iter()
                         .any(|&c| voronoi.is_border_point(c))
@@ -544,17 +802,18 @@ fn draw_coastline(
         }
     };
 
-    let connect_vertices = |start: EdgeIndex, ty: Coast| {
+    let connect_vertices = |cell: usize, start: VertexIndex, ty: Coast| -> Result<_, MapError> {
         let mut chain = Vec::new();
 
         let mut current = start;
         for _ in 0..10_000 {
+            cancel.check()?;
             let prev = chain.last().copied();
 
             chain.push(current);
 
-            let c = &voronoi.vertices[&current].connected_cells;
-            let v = &voronoi.vertices[&current].connected_vertices;
+            let c = &voronoi.vertex(current).connected_cells;
+            let v = &voronoi.vertex(current).connected_vertices;
 
             // Is this vertex is on the border or for a cell of the right coast type
             let c0 = voronoi.is_border_point(c[0]) || coasts[c[0].as_usize()] == ty;
@@ -564,13 +823,16 @@ fn draw_coastline(
             // If the connected vertex is not the previous in the chain and it
             // is between coast types (or next to a border), make it the next
             // vertex
-            if v[0] != prev && c0 != c1 {
-                current = v[0].expect("Tried unwrapping connected vertex");
+            let next = if v[0] != prev && c0 != c1 {
+                v[0]
             } else if v[1] != prev && c1 != c2 {
-                current = v[1].expect("Tried unwrapping connected vertex");
+                v[1]
             } else if v[2] != prev && c2 != c0 {
-                current = v[2].expect("Tried unwrapping connected vertex");
-            }
+                v[2]
+            } else {
+                Some(current)
+            };
+            current = next.ok_or(MapError::BrokenContour { cell })?;
 
             if current == *chain.last().unwrap() {
                 err!("Next vertex not found");
@@ -583,17 +845,17 @@ fn draw_coastline(
 
         // Make the chain circular.
         chain.push(chain[0]);
-        chain
+        Ok(chain)
     };
 
-    for i in 0..voronoi.cells.len() {
+    for i in 0..voronoi.cell_count() {
         let start_from_edge = i == 0 && heights[i] >= OCEAN_HEIGHT;
         if !start_from_edge && coasts[i] == Coast::None {
             // non-edge cell
             continue;
         }
 
-        let f = feature_map[i].expect("No mapped feature");
+        let f = feature_map[i].ok_or(MapError::UnmappedCell(i))?;
         if used[f] || features[f].ty == FeatureType::Ocean {
             continue;
         }
@@ -609,31 +871,32 @@ fn draw_coastline(
         }
         let start = start.unwrap();
 
-        let connected_vertices = connect_vertices(start, ty);
+        let connected_vertices = connect_vertices(i, start, ty)?;
 
         used[f] = true;
         let points: Vec<_> = connected_vertices
             .iter()
-            .map(|v| voronoi.vertices[v].coords)
+            .map(|&v| voronoi.vertex(v).coords)
             .collect();
 
         // TODO: round coordinates
-        let path: Value = basis_curve_closed_line_gen(&points).into();
+        let path = contour_path(voronoi, points, voronoi.cell(i.into()).centroid);
         let id = format!("{}{}", features[f].ty, features[f].index);
         if let FeatureType::Lake(_) = features[f].ty {
-            land_mask_paths.push(path.to_string());
-            lake_paths.push(path.to_string());
+            land_mask_paths.push(path.clone());
+            lake_paths.push(path);
             lake_groups.push(format!("{}", features[f].ty));
             lake_ids.push(id);
         } else {
-            land_mask_paths.push(path.to_string());
-            water_mask_paths.push(path.to_string());
-            coastline_paths.push(path.to_string());
+            land_mask_paths.push(path.clone());
+            water_mask_paths.push(path.clone());
+            coastline_paths.push(path);
             coastline_ids.push(id);
         }
     }
 
     _draw_coastline(
+        dir,
         &land_mask_paths,
         &water_mask_paths,
         &coastline_paths,
@@ -641,13 +904,15 @@ fn draw_coastline(
         &lake_groups,
         &lake_paths,
         &lake_ids,
-    );
+    )?;
 
     time_end!("draw_coastline");
+    Ok(())
 }
 
 #[cfg(target_arch = "wasm32")]
 fn _draw_coastline(
+    dir: &Path,
     land_mask_paths: &[String],
     water_mask_paths: &[String],
     coastline_paths: &[String],
@@ -655,7 +920,7 @@ fn _draw_coastline(
     lake_groups: &[String],
     lake_paths: &[String],
     lake_ids: &[String],
-) {
+) -> Result<(), MapError> {
     __draw_coastline(
         land_mask_paths.iter().map(|s| JsString::from(s.as_str())).collect(),
         water_mask_paths.iter().map(|s| JsString::from(s.as_str())).collect(),
@@ -665,40 +930,39 @@ fn _draw_coastline(
         lake_paths.iter().map(|s| JsString::from(s.as_str())).collect(),
         lake_ids.iter().map(|s| JsString::from(s.as_str())).collect(),
     );
+    Ok(())
 }
 
 // TODO: skip parameter
-fn draw_heightmap(grid: &Grid) {
+fn draw_heightmap(dir: &Path, grid: &Grid, cancel: &CancelToken) -> Result<(), MapError> {
     time_start!("draw_heightmap");
 
-    let mut used = vec![false; grid.voronoi.cells.len()];
+    let mut used = vec![false; grid.voronoi.cell_count()];
     let mut height_paths = Vec::new();
     let mut height_colors = Vec::new();
     let mut height_values = Vec::new();
 
-    let skip = 5;
+    let skip = 5.0;
 
-    let mut current_layer = OCEAN_HEIGHT;
-    let mut ordered_cells: Vec<_> = (0..grid.voronoi.cells.len()).collect();
-    ordered_cells.sort_by_key(|&i| grid.heights[i]);
+    // Contours are traced around the cells at or above each layer, starting
+    // from the cells in the layer.
+    let layer_of = |h: f32| OCEAN_HEIGHT + ((h - OCEAN_HEIGHT) / skip).floor() * skip;
+    let mut ordered_cells: Vec<_> = (0..grid.voronoi.cell_count()).collect();
+    ordered_cells.sort_by(|&a, &b| {
+        grid.heights[a].partial_cmp(&grid.heights[b]).unwrap_or(Ordering::Equal)
+    });
     for i in ordered_cells {
-        let h = grid.heights[i];
-        if h > current_layer {
-            current_layer += skip;
-            if current_layer > WORLD_MAX {
-                break;
-            }
-        }
-        if h < current_layer {
+        if grid.heights[i] < OCEAN_HEIGHT {
             continue;
         }
         if used[i] {
             continue;
         }
+        let h = layer_of(grid.heights[i]);
 
         let on_border = grid
             .voronoi
-            .cells[&i.into()]
+            .cell(i.into())
             .adjacent_cells
             .iter()
             .any(|i| grid.heights[i.as_usize()] < h);
@@ -707,17 +971,17 @@ fn draw_heightmap(grid: &Grid) {
         }
         let vertex = grid
             .voronoi
-            .cells[&i.into()]
+            .cell(i.into())
             .vertices
             .iter()
-            .find(|v|
+            .find(|&&v|
                 grid.voronoi
-                    .vertices[v]
+                    .vertex(v)
                     .connected_cells
                     .iter()
                     .any(|&c| !grid.voronoi.is_border_point(c) && grid.heights[c.as_usize()] < h)
             )
-            .expect("No border vertex found though used for border cell");
+            .ok_or(MapError::BrokenContour { cell: i })?;
 
         let chain = {
             let mut chain = Vec::new();
@@ -725,15 +989,16 @@ fn draw_heightmap(grid: &Grid) {
             let start = *vertex;
             let mut current = start;
             for _ in 0..20_000 {
+                cancel.check()?;
                 let prev = chain.last().copied();
 
                 chain.push(current);
 
-                let c = &grid.voronoi.vertices[&current].connected_cells;
-                let v = &grid.voronoi.vertices[&current].connected_vertices;
+                let c = &grid.voronoi.vertex(current).connected_cells;
+                let v = &grid.voronoi.vertex(current).connected_vertices;
 
                 for cell in c.iter() {
-                    if !grid.voronoi.is_border_point(*cell) && grid.heights[cell.as_usize()] == h {
+                    if !grid.voronoi.is_border_point(*cell) && layer_of(grid.heights[cell.as_usize()]) == h {
                         used[cell.as_usize()] = true;
                     }
                 }
@@ -746,13 +1011,16 @@ fn draw_heightmap(grid: &Grid) {
                 // If the connected vertex is not the previous in the chain and it
                 // is between coast types (or next to a border), make it the next
                 // vertex
-                if v[0] != prev && c0 != c1 {
-                    current = v[0].expect("Tried unwrapping connected vertex");
+                let next = if v[0] != prev && c0 != c1 {
+                    v[0]
                 } else if v[1] != prev && c1 != c2 {
-                    current = v[1].expect("Tried unwrapping connected vertex");
+                    v[1]
                 } else if v[2] != prev && c2 != c0 {
-                    current = v[2].expect("Tried unwrapping connected vertex");
-                }
+                    v[2]
+                } else {
+                    Some(current)
+                };
+                current = next.ok_or(MapError::BrokenContour { cell: i })?;
 
                 if current == *chain.last().unwrap() {
                     err!("Next vertex not found");
@@ -769,34 +1037,102 @@ fn draw_heightmap(grid: &Grid) {
             continue;
         }
         // TODO: line simplification
-        let points: Vec<_> = chain.iter().map(|e| grid.voronoi.vertices[e].coords).collect();
+        let points: Vec<_> = chain.iter().map(|&v| grid.voronoi.vertex(v).coords).collect();
 
-        let path: Value = basis_curve_closed_line_gen(&points).into();
-        height_paths.push(path.to_string());
-        height_colors.push(format!{"#00{:02x}00", current_layer * 2});
+        let path = contour_path(&grid.voronoi, points, grid.voronoi.cell(i.into()).centroid);
+        height_paths.push(path);
+        height_colors.push(format!{"#00{:02x}00", (h * 2.0) as u8});
         height_values.push(h);
     }
 
     _draw_heightmap(
+        dir,
         &height_paths,
         &height_colors,
         &height_values,
-    );
+    )?;
 
     time_end!("draw_heightmap");
+    Ok(())
 }
 
 #[cfg(target_arch = "wasm32")]
 fn _draw_heightmap(
+    dir: &Path,
     height_paths: &[String],
     height_colors: &[String],
-    height_values: &[u8],
-) {
+    height_values: &[f32],
+) -> Result<(), MapError> {
     __draw_heightmap(
         height_paths.iter().map(|s| JsString::from(s.as_str())).collect(),
         height_colors.iter().map(|s| JsString::from(s.as_str())).collect(),
         &height_values,
     );
+    Ok(())
+}
+
+/// The SVG path of a traced contour.
+///
+/// On a map that wraps around, a contour across the seam is made connected
+/// and drawn a second time a map width over, so it shows on both sides. A
+/// contour winding all the way around the map is laid end to end past both
+/// sides and closed off the map, on the side that keeps `inside` within it.
+fn contour_path(voronoi: &Voronoi, mut points: Vec<Point>, inside: Point) -> String {
+    let size = voronoi.size();
+    let width = size.width as f32;
+    let height = size.height as f32;
+    let wraps = voronoi.topology().wraps_horizontally();
+    let turns = if wraps { topology::unwrap_path(&mut points, size) } else { 0 };
+
+    let contours = if !wraps {
+        vec![points]
+    } else if turns == 0 {
+        let min_x = points.iter().map(|p| p.x).fold(std::f32::INFINITY, f32::min);
+        let max_x = points.iter().map(|p| p.x).fold(std::f32::NEG_INFINITY, f32::max);
+        let shift = if min_x < 0.0 {
+            width
+        } else if max_x > width {
+            -width
+        } else {
+            0.0
+        };
+        if shift == 0.0 {
+            vec![points]
+        } else {
+            let copy = points.iter().map(|p| Point::new(p.x + shift, p.y)).collect();
+            vec![points, copy]
+        }
+    } else {
+        let span = turns as f32 * width;
+        let band: Vec<_> = (-1..=1)
+            .flat_map(|k| points.iter().map(move |p| Point::new(p.x + k as f32 * span, p.y)))
+            .collect();
+        let inside = Point::new(
+            points[0].x + span.signum() * (span.signum() * (inside.x - points[0].x)).rem_euclid(width),
+            inside.y,
+        );
+        let close = |y: f32| {
+            let mut contour = band.clone();
+            contour.push(Point::new(band[band.len() - 1].x, y));
+            contour.push(Point::new(band[0].x, y));
+            contour
+        };
+        let above = close(-height);
+        if geometry::contains(&above, inside) {
+            vec![above]
+        } else {
+            vec![close(2.0 * height)]
+        }
+    };
+
+    contours
+        .iter()
+        .map(|contour| {
+            let path: Value = basis_curve_closed_line_gen(contour).into();
+            path.to_string()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
 fn basis_curve_closed_line_gen(points: &[Point]) -> Data {
@@ -0,0 +1,149 @@
+//! Nearest-point queries over the grid points.
+
+use triangulation::Point;
+
+use crate::Size;
+use crate::voronoi::CellIndex;
+
+/// Bucket grid over the points of a `Grid`.
+///
+/// The nearest point to a position is the one whose voronoi cell contains
+/// it, so this finds the cell under any position regardless of how the points
+/// were placed. Each bucket is about one point spacing wide, so a query only
+/// looks at a handful of points.
+#[derive(Default)]
+pub struct PointLocator {
+    bucket_size: f32,
+    buckets_x: usize,
+    buckets_y: usize,
+    // The points in bucket `i` are those between `offsets[i]` and
+    // `offsets[i + 1]`.
+    offsets: Vec<u32>,
+    coords: Vec<Point>,
+    cells: Vec<CellIndex>,
+}
+
+impl PointLocator {
+    pub fn new(size: Size, spacing: f32, points: &[Point]) -> Self {
+        let bucket_size = spacing.max(1.0);
+        let buckets_x = ((size.width as f32 / bucket_size).ceil() as usize).max(1);
+        let buckets_y = ((size.height as f32 / bucket_size).ceil() as usize).max(1);
+        let mut locator = PointLocator {
+            bucket_size,
+            buckets_x,
+            buckets_y,
+            offsets: vec![0; buckets_x * buckets_y + 1],
+            coords: vec![Point::new(0.0, 0.0); points.len()],
+            cells: vec![CellIndex::default(); points.len()],
+        };
+
+        // Count the points per bucket, then place each after the ones of all
+        // earlier buckets.
+        let buckets: Vec<_> = points.iter().map(|p| locator.bucket(p.x, p.y)).collect();
+        for &b in &buckets {
+            locator.offsets[b + 1] += 1;
+        }
+        for b in 0..buckets_x * buckets_y {
+            locator.offsets[b + 1] += locator.offsets[b];
+        }
+        let mut next = locator.offsets.clone();
+        for (i, (&b, &p)) in buckets.iter().zip(points).enumerate() {
+            let slot = next[b] as usize;
+            locator.coords[slot] = p;
+            locator.cells[slot] = i.into();
+            next[b] += 1;
+        }
+
+        locator
+    }
+
+    fn bucket_coords(&self, x: f32, y: f32) -> (usize, usize) {
+        let bx = (x / self.bucket_size).max(0.0) as usize;
+        let by = (y / self.bucket_size).max(0.0) as usize;
+        (bx.min(self.buckets_x - 1), by.min(self.buckets_y - 1))
+    }
+
+    fn bucket(&self, x: f32, y: f32) -> usize {
+        let (bx, by) = self.bucket_coords(x, y);
+        by * self.buckets_x + bx
+    }
+
+    /// The point nearest to `(x, y)`, `None` if there are no points.
+    /// Positions off the map give the nearest cell on it.
+    pub fn nearest(&self, x: f32, y: f32) -> Option<CellIndex> {
+        if self.cells.is_empty() {
+            return None;
+        }
+
+        let (bx, by) = self.bucket_coords(x, y);
+        let mut best = None;
+        let mut best_distance = std::f32::INFINITY;
+        let max_ring = self.buckets_x.max(self.buckets_y);
+        for ring in 0..=max_ring {
+            // Every point in this ring is at least this far away.
+            let min_distance = (ring.saturating_sub(1) as f32) * self.bucket_size;
+            if best.is_some() && min_distance * min_distance > best_distance {
+                break;
+            }
+
+            let x_range = bx.saturating_sub(ring)..=(bx + ring).min(self.buckets_x - 1);
+            let y_range = by.saturating_sub(ring)..=(by + ring).min(self.buckets_y - 1);
+            for cy in y_range {
+                for cx in x_range.clone() {
+                    // Only the outline of the ring, the inside was searched
+                    // before.
+                    let on_ring = cx + ring == bx || cx == bx + ring || cy + ring == by || cy == by + ring;
+                    if !on_ring {
+                        continue;
+                    }
+                    let b = cy * self.buckets_x + cx;
+                    for slot in self.offsets[b] as usize..self.offsets[b + 1] as usize {
+                        let p = self.coords[slot];
+                        let distance = (p.x - x).powi(2) + (p.y - y).powi(2);
+                        if distance < best_distance {
+                            best_distance = distance;
+                            best = Some(self.cells[slot]);
+                        }
+                    }
+                }
+            }
+        }
+
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    fn distance(p: Point, x: f32, y: f32) -> f32 {
+        (p.x - x).powi(2) + (p.y - y).powi(2)
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let points: Vec<_> = (0..600)
+            .map(|_| Point::new(rng.gen_range(0.0, 300.0), rng.gen_range(0.0, 200.0)))
+            .collect();
+        let locator = PointLocator::new(Size::new(300, 200), 10.0, &points);
+
+        // Off the map as well.
+        for _ in 0..2000 {
+            let (x, y) = (rng.gen_range(-50.0, 350.0), rng.gen_range(-50.0, 250.0));
+            let nearest = locator.nearest(x, y).unwrap();
+            let best = points.iter().map(|&p| distance(p, x, y)).fold(std::f32::INFINITY, f32::min);
+            assert_eq!(distance(points[nearest.as_usize()], x, y), best, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn no_points_have_no_nearest() {
+        let locator = PointLocator::new(Size::new(100, 100), 10.0, &[]);
+        assert_eq!(locator.nearest(50.0, 50.0), None);
+    }
+}
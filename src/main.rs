@@ -0,0 +1,326 @@
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rand::random;
+
+use mapgen::{
+    Map,
+    MapError,
+    MapOptions,
+    Permalink,
+    Pipeline,
+    PointDistribution,
+    Size,
+    Topology,
+    CELLS_FILE,
+    COASTLINE_FILE,
+    HEIGHTMAP_FILE,
+};
+use mapgen::azgaar::AzgaarMap;
+use mapgen::heightmap::Template;
+use mapgen::image::{GrayImage, ImageFormat};
+use mapgen::mask::Mask;
+use mapgen::pipeline::ImageHeightmapStage;
+use mapgen::raster::{self, BitDepth, Sampling};
+
+const DEFAULT_SEA_LEVEL: f32 = 0.5;
+
+const USAGE: &str = "\
+Usage: mapgen [OPTIONS]
+
+Generate a map and write its layers as SVG files.
+
+Options given on the command line override those read from --options.
+
+Options:
+    -c, --options <FILE>       Read map options from a .json or .toml file
+    -l, --link <LINK>          Seed and options from a permalink or its query string
+    -s, --seed <SEED>          Seed to generate from [default: random]
+        --size <WxH>           Map size in pixels [default: 1000x1000]
+    -n, --cells <COUNT>        Number of cells to aim for [default: 10000]
+    -t, --template <TEMPLATE>  Heightmap template, or random to pick one [default: random]
+        --heightmap <IMAGE>    Take the heights from a grayscale PNG or PGM image
+                               instead of a template
+        --erosion <PASSES>     Passes of hydraulic and thermal erosion [default: 0]
+        --sea-level <LEVEL>    Image luminance from 0 to 1 where land begins [default: 0.5]
+    -p, --points <MODE>        Point placement: jittered, poisson or lloyd[:N] [default: jittered]
+        --topology <SHAPE>     Map surface: flat, cylinder or sphere [default: flat]
+        --mask <SHAPE>         Map outline: circle or hexagon, with :exclude to drop the
+                               cells outside instead of sinking them [default: none]
+    -o, --output <DIR>         Directory to write the layers to [default: .]
+        --save <FILE>          Also save the complete map to FILE
+        --export-heightmap <FILE>
+                               Also write the heights as a grayscale .png or .pgm image
+        --export-size <WxH>    Size of the exported image [default: map size]
+        --export-depth <BITS>  Bits per pixel of the exported image, 8 or 16 [default: 16]
+        --export-sampling <MODE>
+                               Fill in the exported image from the nearest cell, or
+                               linearly between cells [default: linear]
+        --load <FILE>          Draw a saved map instead of generating one
+    -h, --help                 Print this message
+
+Files ending in .map are read and written in the format of the original
+JavaScript generator.";
+
+struct Args {
+    options_file: Option<PathBuf>,
+    link: Option<Permalink>,
+    seed: Option<u64>,
+    size: Option<Size>,
+    cells: Option<u32>,
+    template: Option<Option<Template>>,
+    points: Option<PointDistribution>,
+    topology: Option<Topology>,
+    mask: Option<Mask>,
+    erosion: Option<u32>,
+    heightmap: Option<GrayImage>,
+    sea_level: f32,
+    output: PathBuf,
+    save: Option<PathBuf>,
+    export: Option<PathBuf>,
+    export_size: Option<Size>,
+    export_depth: BitDepth,
+    export_sampling: Sampling,
+    load: Option<PathBuf>,
+}
+
+impl Args {
+    fn parse() -> Result<Self, String> {
+        let mut args = Args {
+            options_file: None,
+            link: None,
+            seed: None,
+            size: None,
+            cells: None,
+            template: None,
+            points: None,
+            topology: None,
+            mask: None,
+            erosion: None,
+            heightmap: None,
+            sea_level: DEFAULT_SEA_LEVEL,
+            output: PathBuf::from("."),
+            save: None,
+            export: None,
+            export_size: None,
+            export_depth: BitDepth::Sixteen,
+            export_sampling: Sampling::Linear,
+            load: None,
+        };
+
+        let mut argv = env::args().skip(1);
+        while let Some(arg) = argv.next() {
+            if arg == "-h" || arg == "--help" {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+
+            let value = argv.next().ok_or_else(|| format!("missing value for {}", arg))?;
+            match arg.as_str() {
+                "-c" | "--options" => args.options_file = Some(PathBuf::from(value)),
+                "-l" | "--link" => args.link = Some(Permalink::parse(&value).map_err(|e| format!("{}", e))?),
+                "-s" | "--seed" => {
+                    args.seed = Some(value.parse().map_err(|_| format!("invalid seed \"{}\"", value))?);
+                }
+                "--size" => args.size = Some(value.parse().map_err(|e| format!("{}", e))?),
+                "-n" | "--cells" => {
+                    args.cells = Some(
+                        value.parse().map_err(|_| format!("invalid cell count \"{}\"", value))?,
+                    );
+                }
+                "-t" | "--template" if value.eq_ignore_ascii_case("random") => args.template = Some(None),
+                "-t" | "--template" => {
+                    args.template = Some(Some(value.parse().map_err(|e| format!("{}", e))?));
+                }
+                "-p" | "--points" => args.points = Some(value.parse().map_err(|e| format!("{}", e))?),
+                "--topology" => args.topology = Some(value.parse().map_err(|e| format!("{}", e))?),
+                "--mask" => args.mask = Some(value.parse().map_err(|e| format!("{}", e))?),
+                "--erosion" => {
+                    args.erosion = Some(
+                        value.parse().map_err(|_| format!("invalid number of erosion passes \"{}\"", value))?,
+                    );
+                }
+                "--heightmap" => args.heightmap = Some(read_image(Path::new(&value))?),
+                "--sea-level" => {
+                    args.sea_level = value
+                        .parse()
+                        .ok()
+                        .filter(|level| (0.0..=1.0).contains(level))
+                        .ok_or_else(|| format!("invalid sea level \"{}\", expected 0 to 1", value))?;
+                }
+                "-o" | "--output" => args.output = PathBuf::from(value),
+                "--save" => args.save = Some(PathBuf::from(value)),
+                "--export-heightmap" => args.export = Some(PathBuf::from(value)),
+                "--export-size" => args.export_size = Some(value.parse().map_err(|e| format!("{}", e))?),
+                "--export-depth" => args.export_depth = value.parse()?,
+                "--export-sampling" => args.export_sampling = value.parse()?,
+                "--load" => args.load = Some(PathBuf::from(value)),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+
+        Ok(args)
+    }
+
+    fn options(&self) -> Result<MapOptions, String> {
+        let mut options = match (&self.link, &self.options_file) {
+            (Some(link), _) => link.options.clone(),
+            (None, Some(path)) => read_options(path)?,
+            (None, None) => MapOptions::default(),
+        };
+        if let Some(size) = self.size {
+            options.size = size;
+        }
+        if let Some(cells) = self.cells {
+            options.cells = cells;
+        }
+        if let Some(template) = self.template {
+            options.template = template;
+        }
+        if let Some(points) = self.points {
+            options.point_distribution = points;
+        }
+        if let Some(topology) = self.topology {
+            options.topology = topology;
+        }
+        if let Some(iterations) = self.erosion {
+            options.erosion.iterations = iterations;
+        }
+        if let Some(mask) = &self.mask {
+            options.mask = Some(mask.clone());
+        }
+        Ok(options)
+    }
+}
+
+fn read_options(path: &Path) -> Result<MapOptions, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    let options = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => MapOptions::from_toml(&contents),
+        _ => MapOptions::from_json(&contents),
+    };
+    options.map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read_image(path: &Path) -> Result<GrayImage, String> {
+    let data = fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    GrayImage::decode(&data).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn is_azgaar_file(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e == "map")
+}
+
+fn load_map(path: &Path) -> Result<Map, Box<dyn Error>> {
+    if is_azgaar_file(path) {
+        let data = fs::read_to_string(path)?;
+        Ok(AzgaarMap::parse(&data)?.to_map()?)
+    } else {
+        Ok(Map::load(BufReader::new(File::open(path)?))?)
+    }
+}
+
+fn generate_map(options: MapOptions, seed: Option<u64>, args: &Args) -> Result<Map, MapError> {
+    let mut pipeline = Pipeline::default();
+    if let Some(image) = &args.heightmap {
+        pipeline.replace("heightmap", ImageHeightmapStage::new(image.clone(), args.sea_level))?;
+    }
+    pipeline.run(options, seed.unwrap_or_else(random))
+}
+
+fn save_map(map: &Map, path: &Path) -> Result<(), Box<dyn Error>> {
+    if is_azgaar_file(path) {
+        fs::write(path, AzgaarMap::from_map(map)?.to_string())?;
+    } else {
+        map.save(BufWriter::new(File::create(path)?))?;
+    }
+    Ok(())
+}
+
+fn export_heightmap(map: &Map, path: &Path, args: &Args) -> Result<(), Box<dyn Error>> {
+    let size = args.export_size.unwrap_or(map.grid.size);
+    let image = raster::heightmap_image(&map.grid, size, args.export_sampling, args.export_depth)?;
+    let format = ImageFormat::from_extension(path.extension().and_then(|e| e.to_str()).unwrap_or(""));
+    fs::write(path, image.encode(format)?)?;
+    Ok(())
+}
+
+fn main() {
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let options = match args.options() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = fs::create_dir_all(&args.output) {
+        eprintln!("error: could not create {}: {}", args.output.display(), e);
+        process::exit(1);
+    }
+
+    let seed = args.seed.or_else(|| args.link.as_ref().map(|l| l.seed));
+    let map = match (&args.load, seed) {
+        (Some(path), _) => match load_map(path) {
+            Ok(map) => map,
+            Err(e) => {
+                eprintln!("error: could not load {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        },
+        (None, seed) => {
+            match generate_map(options, seed, &args) {
+                Ok(map) => map,
+                Err(e) => {
+                    eprintln!("error: could not generate map: {}", e);
+                    process::exit(1);
+                }
+            }
+        }
+    };
+
+    if let Some(path) = &args.save {
+        if let Err(e) = save_map(&map, path) {
+            eprintln!("error: could not save {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.export {
+        if let Err(e) = export_heightmap(&map, path, &args) {
+            eprintln!("error: could not export {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+
+    if let Err(e) = map.draw_to(&args.output) {
+        eprintln!("error: could not draw map: {}", e);
+        process::exit(1);
+    }
+
+    println!("seed: {}", map.seed);
+    if let Some(template) = &map.template {
+        println!("template: {}", template);
+    }
+    if let Some(link) = Permalink::from_map(&map) {
+        println!("link: ?{}", link.to_query());
+    }
+    for file in &[CELLS_FILE, COASTLINE_FILE, HEIGHTMAP_FILE] {
+        println!("wrote {}", args.output.join(file).display());
+    }
+    if let Some(path) = &args.export {
+        println!("wrote {}", path.display());
+    }
+}
@@ -0,0 +1,344 @@
+//! Masks that give a map a shape other than its rectangle.
+//!
+//! A mask is applied to the heightmap before the features are marked. Cells
+//! outside of it are either sunk into the ocean, or excluded from the grid
+//! entirely by turning their points into boundary points, so the map is
+//! fenced in along the mask the way it is along its edges.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use triangulation::Point;
+
+use crate::{Grid, MapError, Size, Topology};
+use crate::geometry;
+use crate::heightmap::OCEAN_HEIGHT;
+use crate::{time_end, time_start};
+
+/// Bitmap pixels at least this light are inside the mask.
+const BITMAP_THRESHOLD: u8 = 128;
+
+/// The outline of a mask.
+///
+/// Serialized with the variant in a `type` field, as TOML has no
+/// representation for enum variants with data.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum MaskShape {
+    /// The largest circle centered on the map.
+    Circle,
+    /// The largest regular hexagon centered on the map, with flat top and
+    /// bottom edges.
+    Hexagon,
+    /// A polygon in map coordinates.
+    Polygon {
+        vertices: Vec<[f32; 2]>,
+    },
+    /// A grayscale image stretched over the map, row by row from the top
+    /// left. Light pixels are inside the mask.
+    Bitmap {
+        width: u32,
+        height: u32,
+        pixels: Vec<u8>,
+    },
+}
+
+/// What happens to the cells outside of a mask.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum MaskMode {
+    /// Lower them into the ocean.
+    Ocean,
+    /// Remove them from the grid. Spherical grids have no boundary to fence
+    /// the remaining cells in, so there they are lowered into the ocean.
+    Exclude,
+}
+
+impl Default for MaskMode {
+    fn default() -> Self {
+        MaskMode::Ocean
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mask {
+    // Comes first, as the shape may be serialized as a table for TOML.
+    #[serde(default)]
+    pub mode: MaskMode,
+    pub shape: MaskShape,
+}
+
+impl Mask {
+    pub fn new(shape: MaskShape, mode: MaskMode) -> Self {
+        Mask {
+            mode,
+            shape,
+        }
+    }
+
+    /// Whether position `p` on a map of `size` is inside the mask.
+    pub fn contains(&self, p: Point, size: Size) -> bool {
+        let width = size.width as f32;
+        let height = size.height as f32;
+        let dx = (p.x - width / 2.0).abs();
+        let dy = (p.y - height / 2.0).abs();
+        match &self.shape {
+            MaskShape::Circle => {
+                let radius = width.min(height) / 2.0;
+                dx * dx + dy * dy <= radius * radius
+            }
+            MaskShape::Hexagon => {
+                let sqrt3 = 3f32.sqrt();
+                let radius = (width / 2.0).min(height / sqrt3);
+                dy <= sqrt3 / 2.0 * radius && sqrt3 * dx + dy <= sqrt3 * radius
+            }
+            MaskShape::Polygon { vertices } => {
+                let polygon: Vec<_> = vertices.iter().map(|&[x, y]| Point::new(x, y)).collect();
+                geometry::contains(&polygon, p)
+            }
+            MaskShape::Bitmap { width: bitmap_width, height: bitmap_height, pixels } => {
+                let x = ((p.x / width * *bitmap_width as f32).max(0.0) as u32).min(bitmap_width - 1);
+                let y = ((p.y / height * *bitmap_height as f32).max(0.0) as u32).min(bitmap_height - 1);
+                pixels[(y * bitmap_width + x) as usize] >= BITMAP_THRESHOLD
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), MapError> {
+        match &self.shape {
+            MaskShape::Polygon { vertices } if vertices.len() < 3 =>
+                Err(MapError::InvalidMask("a polygon needs at least 3 vertices")),
+            MaskShape::Bitmap { width, height, .. } if *width == 0 || *height == 0 =>
+                Err(MapError::InvalidMask("the bitmap is empty")),
+            MaskShape::Bitmap { width, height, pixels }
+                if pixels.len() != *width as usize * *height as usize =>
+                Err(MapError::InvalidMask("the number of pixels does not match the bitmap size")),
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the mask to a grid with its heights generated, before its
+    /// features are marked.
+    pub fn apply(&self, grid: &mut Grid) -> Result<(), MapError> {
+        time_start!("apply_mask");
+        self.validate()?;
+
+        let inside: Vec<_> = grid.points
+            .iter()
+            .take(grid.voronoi.cell_count())
+            .map(|&p| self.contains(p, grid.size))
+            .collect();
+        if self.mode == MaskMode::Exclude && grid.topology != Topology::Sphere {
+            exclude(grid, &inside)?;
+        } else {
+            for (height, &inside) in grid.heights.iter_mut().zip(&inside) {
+                if !inside {
+                    *height = (*height).min(OCEAN_HEIGHT - 1.0);
+                }
+            }
+        }
+
+        time_end!("apply_mask");
+        Ok(())
+    }
+}
+
+// Rebuild the grid from the points inside, with the others added to the
+// boundary.
+fn exclude(grid: &mut Grid, inside: &[bool]) -> Result<(), MapError> {
+    if inside.iter().all(|&inside| inside) {
+        return Ok(());
+    }
+    if !inside.iter().any(|&inside| inside) {
+        return Err(MapError::InvalidMask("no cells are inside the mask"));
+    }
+
+    let mut points = Vec::new();
+    let mut heights = Vec::new();
+    let mut boundary = grid.boundary.clone();
+    for (i, &point) in grid.points.iter().enumerate() {
+        if inside[i] {
+            points.push(point);
+            heights.push(grid.heights[i]);
+        } else {
+            boundary.push(point);
+        }
+    }
+
+    let mut masked = Grid::from_points(
+        grid.size,
+        grid.cells_desired,
+        grid.distribution,
+        grid.topology,
+        grid.point_spacing,
+        boundary,
+        points,
+    )?;
+    masked.heights = heights;
+    *grid = masked;
+    Ok(())
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseMaskError(String);
+
+impl fmt::Display for ParseMaskError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown mask \"{}\", expected circle or hexagon, optionally followed by :ocean or :exclude",
+            self.0,
+        )
+    }
+}
+
+impl std::error::Error for ParseMaskError {}
+
+impl FromStr for Mask {
+    type Err = ParseMaskError;
+
+    /// Parse one of the built-in shapes case-insensitively, optionally
+    /// followed by the mode, e.g. `hexagon:exclude`. The mode defaults to
+    /// ocean.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParseMaskError(s.to_owned());
+        let normalized = s.trim().to_lowercase();
+        let mut parts = normalized.splitn(2, ':');
+        let shape = match parts.next().unwrap_or("") {
+            "circle" | "round" => MaskShape::Circle,
+            "hexagon" | "hex" => MaskShape::Hexagon,
+            _ => return Err(err()),
+        };
+        let mode = match parts.next() {
+            None | Some("ocean") => MaskMode::Ocean,
+            Some("exclude") => MaskMode::Exclude,
+            Some(_) => return Err(err()),
+        };
+        Ok(Mask::new(shape, mode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+    use crate::{CancelToken, PointDistribution};
+
+    fn grid(topology: Topology) -> Grid {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut grid = Grid::new(
+            Size::new(300, 200),
+            crate::MIN_CELLS,
+            PointDistribution::Jittered,
+            topology,
+            &mut rng,
+            &CancelToken::new(),
+        ).unwrap();
+        grid.heights = vec![OCEAN_HEIGHT + 10.0; grid.voronoi.cell_count()];
+        grid
+    }
+
+    #[test]
+    fn shapes_contain_the_center_but_not_the_corners() {
+        let size = Size::new(300, 200);
+        let shapes = vec![
+            MaskShape::Circle,
+            MaskShape::Hexagon,
+            MaskShape::Polygon {
+                vertices: vec![[150.0, 10.0], [290.0, 100.0], [150.0, 190.0], [10.0, 100.0]],
+            },
+            MaskShape::Bitmap {
+                width: 3,
+                height: 3,
+                pixels: vec![0, 0, 0, 0, 255, 0, 0, 0, 0],
+            },
+        ];
+        for shape in shapes {
+            let mask = Mask::new(shape, MaskMode::Ocean);
+            assert!(mask.contains(Point::new(150.0, 100.0), size), "{:?}", mask);
+            for &(x, y) in &[(1.0, 1.0), (299.0, 1.0), (1.0, 199.0), (299.0, 199.0)] {
+                assert!(!mask.contains(Point::new(x, y), size), "{:?} at ({}, {})", mask, x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn sinks_cells_outside_into_the_ocean() {
+        let mut grid = grid(Topology::Flat);
+        let cells = grid.voronoi.cell_count();
+        let mask = Mask::new(MaskShape::Circle, MaskMode::Ocean);
+        mask.apply(&mut grid).unwrap();
+
+        assert_eq!(grid.voronoi.cell_count(), cells);
+        for (i, &p) in grid.points.iter().take(cells).enumerate() {
+            assert_eq!(grid.heights[i] >= OCEAN_HEIGHT, mask.contains(p, grid.size), "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn excludes_cells_outside() {
+        let mut grid = grid(Topology::Flat);
+        let cells = grid.voronoi.cell_count();
+        let mask = Mask::new(MaskShape::Hexagon, MaskMode::Exclude);
+        mask.apply(&mut grid).unwrap();
+
+        assert!(grid.voronoi.cell_count() < cells);
+        assert_eq!(grid.heights.len(), grid.voronoi.cell_count());
+        assert!(grid.points.iter().take(grid.voronoi.cell_count()).all(|&p| mask.contains(p, grid.size)));
+        assert!(grid.heights.iter().all(|&h| h >= OCEAN_HEIGHT));
+    }
+
+    #[test]
+    fn sphere_sinks_excluded_cells() {
+        let mut grid = grid(Topology::Sphere);
+        let cells = grid.voronoi.cell_count();
+        Mask::new(MaskShape::Circle, MaskMode::Exclude).apply(&mut grid).unwrap();
+        assert_eq!(grid.voronoi.cell_count(), cells);
+        assert!(grid.heights.iter().any(|&h| h < OCEAN_HEIGHT));
+    }
+
+    #[test]
+    fn rejects_invalid_masks() {
+        let invalid = vec![
+            MaskShape::Polygon {
+                vertices: vec![[0.0, 0.0], [1.0, 1.0]],
+            },
+            MaskShape::Bitmap {
+                width: 0,
+                height: 1,
+                pixels: Vec::new(),
+            },
+            MaskShape::Bitmap {
+                width: 2,
+                height: 2,
+                pixels: vec![255; 3],
+            },
+        ];
+        for shape in invalid {
+            let mut grid = grid(Topology::Flat);
+            match Mask::new(shape, MaskMode::Ocean).apply(&mut grid) {
+                Err(MapError::InvalidMask(_)) => {}
+                other => panic!("expected an invalid mask, got {:?}", other),
+            }
+        }
+
+        let mut grid = grid(Topology::Flat);
+        let outside = MaskShape::Polygon {
+            vertices: vec![[-10.0, -10.0], [-5.0, -10.0], [-5.0, -5.0]],
+        };
+        match Mask::new(outside, MaskMode::Exclude).apply(&mut grid) {
+            Err(MapError::InvalidMask(_)) => {}
+            other => panic!("expected no cells inside, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_shape_and_mode() {
+        assert_eq!("Hexagon".parse(), Ok(Mask::new(MaskShape::Hexagon, MaskMode::Ocean)));
+        assert_eq!("round:exclude".parse(), Ok(Mask::new(MaskShape::Circle, MaskMode::Exclude)));
+        assert!("circle:sideways".parse::<Mask>().is_err());
+        assert!("square".parse::<Mask>().is_err());
+    }
+}
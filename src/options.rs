@@ -0,0 +1,179 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{PointDistribution, Size, Topology};
+use crate::erosion::Erosion;
+use crate::heightmap::{Template, TemplateWeight};
+use crate::mask::Mask;
+
+/// Every user-tweakable parameter of map generation.
+///
+/// A map is fully determined by its seed and its options. Field names follow
+/// the inputs in `index.html` (`pointsInput`, `templateInput`, ...), minus the
+/// `Input` suffix. Missing fields are filled in from `Default` when loading,
+/// and `from_json`, `from_toml`, permalinks and saves turn the `density` of
+/// older options into the matching cell count.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MapOptions {
+    /// Number of cells to aim for, from `MIN_CELLS` to `MAX_CELLS`.
+    pub cells: u32,
+    /// How the grid points are placed.
+    pub point_distribution: PointDistribution,
+    /// Shape of the surface the map covers.
+    pub topology: Topology,
+    /// Heightmap template, picked at random using `template_weights` if
+    /// none.
+    pub template: Option<Template>,
+    /// Prevailing wind direction in degrees for each of the six latitude
+    /// tiers, from north to south.
+    pub winds: [u16; 6],
+    /// Temperature at the equator in °C.
+    pub temperature_equator: i8,
+    /// Temperature at the poles in °C.
+    pub temperature_pole: i8,
+    /// Precipitation in percent.
+    pub prec: u16,
+    /// Map size relative to the world size in percent.
+    pub map_size: u8,
+    /// North-south shift of the map, 0 being the north pole and 100 the south
+    /// pole.
+    pub latitude: u8,
+    /// Exponent used to convert heights to real-world elevation, see
+    /// `heightmap::elevation`.
+    pub height_exponent: f32,
+    /// Number of cultures.
+    pub cultures: u8,
+    /// Number of states.
+    pub regions: u8,
+    /// Provinces ratio in percent.
+    pub provinces: u8,
+    /// States growth power.
+    pub power: f32,
+    /// Neutral lands growth rate.
+    pub neutral: f32,
+    /// Number of towns. 1000 means the number is chosen automatically.
+    pub manors: u16,
+    /// Number of religions.
+    pub religions: u8,
+    /// Number of people each population point represents.
+    pub population_rate: u16,
+    /// Urbanization rate.
+    pub urbanization: f32,
+    // Serialized as a table, so it has to come after all plain values for
+    // TOML.
+    /// Map size in pixels.
+    pub size: Size,
+    /// Shape of the map within its rectangle, the whole rectangle if none.
+    pub mask: Option<Mask>,
+    /// How often each template is picked when `template` is none.
+    pub template_weights: Vec<TemplateWeight>,
+    /// Erosion of the heightmap.
+    pub erosion: Erosion,
+}
+
+impl Default for MapOptions {
+    fn default() -> Self {
+        MapOptions {
+            cells: 10_000,
+            point_distribution: PointDistribution::Jittered,
+            topology: Topology::Flat,
+            template: None,
+            winds: [225, 45, 225, 315, 135, 315],
+            temperature_equator: 27,
+            temperature_pole: -30,
+            prec: 100,
+            map_size: 100,
+            latitude: 50,
+            height_exponent: 1.8,
+            cultures: 14,
+            regions: 13,
+            provinces: 30,
+            power: 5.0,
+            neutral: 1.0,
+            manors: 1000,
+            religions: 15,
+            population_rate: 1000,
+            urbanization: 1.0,
+            size: Size::new(1000, 1000),
+            mask: None,
+            template_weights: TemplateWeight::defaults(),
+            erosion: Erosion::default(),
+        }
+    }
+}
+
+impl MapOptions {
+    pub fn from_json(s: &str) -> Result<Self, OptionsError> {
+        let mut options = serde_json::from_str(s).map_err(OptionsError::Json)?;
+        serde_json::from_str::<LegacyOptions>(s)
+            .map_err(OptionsError::Json)?
+            .migrate(&mut options);
+        Ok(options)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("MapOptions is always serializable")
+    }
+
+    pub fn from_toml(s: &str) -> Result<Self, OptionsError> {
+        let mut options = toml::from_str(s).map_err(OptionsError::TomlDe)?;
+        toml::from_str::<LegacyOptions>(s)
+            .map_err(OptionsError::TomlDe)?
+            .migrate(&mut options);
+        Ok(options)
+    }
+
+    pub fn to_toml(&self) -> Result<String, OptionsError> {
+        toml::to_string_pretty(self).map_err(OptionsError::TomlSer)
+    }
+}
+
+/// Cells per step of the `density` option that `cells` replaced.
+const DENSITY_STEP: u32 = 10_000;
+
+// Options that have since been replaced, read next to `MapOptions` from the
+// same data to carry them over.
+#[derive(Default, Deserialize)]
+pub(crate) struct LegacyOptions {
+    pub(crate) density: Option<u32>,
+    pub(crate) cells: Option<u32>,
+}
+
+impl LegacyOptions {
+    /// Set `cells` from the density of options written before cell counts,
+    /// unless they have a cell count as well.
+    pub(crate) fn migrate(self, options: &mut MapOptions) {
+        if let (Some(density), None) = (self.density, self.cells) {
+            options.cells = density.saturating_mul(DENSITY_STEP);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum OptionsError {
+    Json(serde_json::Error),
+    TomlDe(toml::de::Error),
+    TomlSer(toml::ser::Error),
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionsError::Json(e) => write!(f, "invalid JSON options: {}", e),
+            OptionsError::TomlDe(e) => write!(f, "invalid TOML options: {}", e),
+            OptionsError::TomlSer(e) => write!(f, "could not write TOML options: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OptionsError::Json(e) => Some(e),
+            OptionsError::TomlDe(e) => Some(e),
+            OptionsError::TomlSer(e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! The packed cell graph, a finer graph over the land for political layers.
+//!
+//! Climate runs on the coarse `Grid`, while states, burgs and the like need
+//! detail along the coasts but nothing out at sea. The pack keeps the land
+//! and coastal water cells of the grid, adds points between neighboring coast
+//! cells and triangulates them again.
+
+use triangulation::Point;
+
+use crate::{Coast, FeatureType, Grid, MapError, Size, Topology};
+use crate::heightmap::OCEAN_HEIGHT;
+use crate::locator::PointLocator;
+use crate::util::FloatExt;
+use crate::voronoi::{CellIndex, Voronoi};
+use crate::{time_end, time_start};
+
+pub struct PackGrid {
+    pub size: Size,
+    pub topology: Topology,
+    pub point_spacing: f32,
+    pub points: Vec<Point>,
+    pub voronoi: Voronoi,
+    pub locator: PointLocator,
+    /// The grid cell each pack cell was made from.
+    pub grid_cells: Vec<CellIndex>,
+    pub heights: Vec<f32>,
+}
+
+impl PackGrid {
+    /// Pack the cells of a grid with features already marked.
+    pub fn new(grid: &Grid) -> Result<Self, MapError> {
+        time_start!("pack_grid");
+
+        let spacing = grid.point_spacing;
+        let mut points = Vec::new();
+        let mut grid_cells = Vec::new();
+        let mut heights = Vec::new();
+        let mut push = |point: Point, cell: CellIndex, height: f32| {
+            points.push(point);
+            grid_cells.push(cell);
+            heights.push(height);
+        };
+
+        for cell in grid.voronoi.cells() {
+            let i = cell.as_usize();
+            let height = grid.heights[i];
+            let coast = grid.coasts[i];
+            let lake = grid.feature_map[i]
+                .map_or(false, |f| match grid.features[f].ty {
+                    FeatureType::Lake(_) => true,
+                    _ => false,
+                });
+            // Open water away from the coast is left out.
+            if height < OCEAN_HEIGHT && coast != Coast::Shallows && !lake {
+                continue;
+            }
+            let point = grid.points[i];
+            push(point, cell, height);
+
+            // Halve the distance between coast cells of the same kind, so
+            // the coastline has more detail.
+            let voronoi_cell = grid.voronoi.cell(cell);
+            if coast == Coast::None || voronoi_cell.border_cell {
+                continue;
+            }
+            for &a in voronoi_cell.adjacent_cells {
+                if a <= cell || grid.coasts[a.as_usize()] != coast {
+                    continue;
+                }
+                let other = grid.points[a.as_usize()];
+                if (point.x - other.x).powi(2) + (point.y - other.y).powi(2) < spacing * spacing {
+                    continue;
+                }
+                let midpoint = grid.topology.midpoint(point, other, grid.size);
+                let midpoint = Point::new(midpoint.x.round_decimals(1), midpoint.y.round_decimals(1));
+                push(midpoint, cell, height);
+            }
+        }
+
+        // Without land a sphere leaves nothing to triangulate, as it has no
+        // boundary points.
+        let voronoi = if points.is_empty() && grid.topology == Topology::Sphere {
+            Voronoi::default()
+        } else {
+            grid.topology.voronoi(grid.size, &points, &grid.boundary)?
+        };
+        let locator = PointLocator::new(grid.size, spacing, &points);
+
+        time_end!("pack_grid");
+
+        Ok(PackGrid {
+            size: grid.size,
+            topology: grid.topology,
+            point_spacing: spacing,
+            points,
+            voronoi,
+            locator,
+            grid_cells,
+            heights,
+        })
+    }
+
+    /// A pack without any cells, to be filled in by the pack stage.
+    pub fn empty(size: Size) -> Self {
+        PackGrid {
+            size,
+            topology: Topology::default(),
+            point_spacing: 0.0,
+            points: Vec::new(),
+            voronoi: Voronoi::default(),
+            locator: PointLocator::default(),
+            grid_cells: Vec::new(),
+            heights: Vec::new(),
+        }
+    }
+
+    /// The pack cell whose polygon contains `(x, y)`, like
+    /// `Grid::coords_to_cell_index`.
+    pub fn coords_to_cell_index(&self, x: f32, y: f32) -> CellIndex {
+        let nearest = self.locator.nearest(x, y).unwrap_or_default();
+        self.topology.refine_nearest(&self.voronoi, &self.points, self.size, nearest, x, y)
+    }
+
+    /// The grid cell a pack cell was made from.
+    pub fn grid_cell(&self, cell: CellIndex) -> CellIndex {
+        self.grid_cells[cell.as_usize()]
+    }
+}
@@ -0,0 +1,344 @@
+//! Encoding of a map's seed and options into a URL query string.
+//!
+//! The query holds `seed`, `width` and `height` like the original generator's
+//! links, followed by every option that differs from its default under the
+//! option's field name, e.g. `?seed=42&width=1920&height=1080&template=Atoll`.
+//! Lists are comma separated and options that are neither numbers, strings nor
+//! lists are written as JSON.
+
+use std::fmt;
+
+use serde_json::{Map as JsonMap, Value};
+
+use crate::{Map, MapError, MapOptions};
+use crate::options::LegacyOptions;
+
+#[derive(Debug, PartialEq)]
+pub enum PermalinkError {
+    MissingSeed,
+    InvalidValue {
+        key: String,
+        value: String,
+    },
+}
+
+impl fmt::Display for PermalinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PermalinkError::MissingSeed => write!(f, "link has no seed"),
+            PermalinkError::InvalidValue { key, value } =>
+                write!(f, "invalid value \"{}\" for {} in link", value, key),
+        }
+    }
+}
+
+impl std::error::Error for PermalinkError {}
+
+/// Everything needed to recreate a map exactly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Permalink {
+    pub seed: u64,
+    pub options: MapOptions,
+}
+
+impl Permalink {
+    pub fn new(seed: u64, options: MapOptions) -> Self {
+        Permalink {
+            seed,
+            options,
+        }
+    }
+
+    /// The link to a generated map. None if its heights did not come from a
+    /// template, e.g. when they were imported from an image or an Azgaar
+    /// file, as the seed and options alone would give a different map.
+    pub fn from_map(map: &Map) -> Option<Self> {
+        if map.template.is_none() {
+            return None;
+        }
+        Some(Permalink::new(map.seed, map.options.clone()))
+    }
+
+    /// Generate the map this link describes.
+    pub fn generate(self) -> Result<Map, MapError> {
+        Map::generate_with_seed(self.options, self.seed)
+    }
+
+    /// Encode as a query string, without the leading `?`.
+    pub fn to_query(&self) -> String {
+        let mut params = vec![
+            ("seed".to_owned(), self.seed.to_string()),
+            ("width".to_owned(), self.options.size.width.to_string()),
+            ("height".to_owned(), self.options.size.height.to_string()),
+        ];
+
+        let defaults = options_to_json(&MapOptions::default());
+        for (key, value) in options_to_json(&self.options) {
+            if key == "size" || defaults.get(&key) == Some(&value) {
+                continue;
+            }
+            params.push((key, encode_value(&value)));
+        }
+
+        params
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, percent_encode(v)))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
+
+    /// Parse a query string as written by `to_query`. A full URL is accepted
+    /// as well, in which case only its query is looked at. Unknown parameters
+    /// are ignored and missing options keep their defaults. The `density` of
+    /// older links becomes the matching cell count, unless `cells` is given.
+    pub fn parse(link: &str) -> Result<Self, PermalinkError> {
+        let query = match link.find('?') {
+            Some(i) => &link[i + 1..],
+            None => link,
+        };
+        let query = query.split('#').next().unwrap_or("");
+
+        let invalid = |key: &str, value: &str| PermalinkError::InvalidValue {
+            key: key.to_owned(),
+            value: value.to_owned(),
+        };
+
+        let mut seed = None;
+        let mut legacy = LegacyOptions::default();
+        let mut size = MapOptions::default().size;
+        let mut options = options_to_json(&MapOptions::default());
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let key = percent_decode(kv.next().unwrap_or(""));
+            let value = percent_decode(kv.next().unwrap_or(""));
+            match key.as_str() {
+                "seed" => seed = Some(value.parse().map_err(|_| invalid(&key, &value))?),
+                "width" => size.width = value.parse().map_err(|_| invalid(&key, &value))?,
+                "height" => size.height = value.parse().map_err(|_| invalid(&key, &value))?,
+                "size" => {}
+                "cells" => {
+                    let cells: u32 = value.parse().map_err(|_| invalid(&key, &value))?;
+                    legacy.cells = Some(cells);
+                    options.insert(key, cells.into());
+                }
+                // Links from before cell counts.
+                "density" => legacy.density = Some(value.parse().map_err(|_| invalid(&key, &value))?),
+                _ => {
+                    if let Some(default) = options.get(&key) {
+                        let parsed = decode_value(default, &value).ok_or_else(|| invalid(&key, &value))?;
+                        options.insert(key, parsed);
+                    }
+                }
+            }
+        }
+
+        let seed = seed.ok_or(PermalinkError::MissingSeed)?;
+        if size.width == 0 || size.height == 0 {
+            return Err(invalid("size", &size.to_string()));
+        }
+        let mut options: MapOptions = serde_json::from_value(Value::Object(options))
+            .map_err(|e| invalid("options", &e.to_string()))?;
+        options.size = size;
+        legacy.migrate(&mut options);
+
+        Ok(Permalink::new(seed, options))
+    }
+}
+
+fn options_to_json(options: &MapOptions) -> JsonMap<String, Value> {
+    match serde_json::to_value(options) {
+        Ok(Value::Object(map)) => map,
+        _ => unreachable!("MapOptions serializes to an object"),
+    }
+}
+
+fn encode_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => encode_number(n),
+        Value::Bool(_) => value.to_string(),
+        // An empty value would not read back as an empty list, so those are
+        // written as JSON.
+        Value::Array(values) if !values.is_empty() && values.iter().all(|v| v.is_number()) =>
+            values.iter().map(encode_value).collect::<Vec<_>>().join(","),
+        _ => value.to_string(),
+    }
+}
+
+fn encode_number(n: &serde_json::Number) -> String {
+    match n.as_f64() {
+        // Options are `f32`, which widen to long decimals. Write the shortest
+        // form that reads back as the same `f32`.
+        Some(v) if n.is_f64() && (v as f32) as f64 == v => (v as f32).to_string(),
+        _ => n.to_string(),
+    }
+}
+
+// Parse `s` as a value of the same kind as `default`.
+fn decode_value(default: &Value, s: &str) -> Option<Value> {
+    match default {
+        Value::String(_) => Some(Value::String(s.to_owned())),
+        Value::Number(_) => decode_number(s),
+        Value::Bool(_) => s.parse().ok().map(Value::Bool),
+        // Options that are unset by default, such as the template, are
+        // written as JSON unless they are plain strings.
+        Value::Null => serde_json::from_str(s).ok().or_else(|| Some(Value::String(s.to_owned()))),
+        Value::Array(values) if values.iter().all(|v| v.is_number()) => s
+            .split(',')
+            .map(decode_number)
+            .collect::<Option<Vec<_>>>()
+            .map(Value::Array),
+        _ => serde_json::from_str(s).ok(),
+    }
+}
+
+fn decode_number(s: &str) -> Option<Value> {
+    if let Ok(v) = s.parse::<u64>() {
+        Some(v.into())
+    } else if let Ok(v) = s.parse::<i64>() {
+        Some(v.into())
+    } else {
+        s.parse::<f64>().ok().and_then(serde_json::Number::from_f64).map(Value::Number)
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b',' =>
+                encoded.push(b as char),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        decoded.push(b);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PointDistribution, Size, Topology};
+    use crate::erosion::Erosion;
+    use crate::heightmap::{Template, TemplateWeight};
+    use crate::mask::{Mask, MaskMode, MaskShape};
+
+    #[test]
+    fn default_options_round_trip() {
+        let link = Permalink::new(42, MapOptions::default());
+        let query = link.to_query();
+        assert_eq!(query, "seed=42&width=1000&height=1000");
+        assert_eq!(Permalink::parse(&query), Ok(link));
+    }
+
+    #[test]
+    fn changed_options_round_trip() {
+        let options = MapOptions {
+            size: Size::new(1920, 1080),
+            cells: 25_000,
+            point_distribution: PointDistribution::Lloyd(2),
+            topology: Topology::Cylinder,
+            template: Some(Template::HighIsland),
+            winds: [0, 90, 180, 270, 45, 135],
+            temperature_pole: -45,
+            height_exponent: 2.1,
+            urbanization: 0.35,
+            mask: Some(Mask::new(MaskShape::Circle, MaskMode::Exclude)),
+            template_weights: vec![
+                TemplateWeight::new(Template::Atoll, 2),
+                TemplateWeight::new(Template::Volcano, 1),
+            ],
+            erosion: Erosion {
+                iterations: 3,
+                evaporation: 0.1,
+                ..Erosion::default()
+            },
+            ..MapOptions::default()
+        };
+        let link = Permalink::new(u64::MAX, options);
+
+        let query = link.to_query();
+        assert!(query.contains("&height_exponent=2.1&"), "{}", query);
+        assert!(query.ends_with("&winds=0,90,180,270,45,135"), "{}", query);
+        assert_eq!(Permalink::parse(&query), Ok(link));
+    }
+
+    #[test]
+    fn empty_template_weights_round_trip() {
+        let options = MapOptions {
+            template_weights: Vec::new(),
+            ..MapOptions::default()
+        };
+        let link = Permalink::new(1, options);
+
+        let query = link.to_query();
+        assert_eq!(query, "seed=1&width=1000&height=1000&template_weights=%5B%5D");
+        assert_eq!(Permalink::parse(&query), Ok(link));
+    }
+
+    #[test]
+    fn parses_full_url() {
+        let link = Permalink::parse(
+            "https://example.com/map?seed=7&width=800&height=600&template=Atoll\
+             &point_distribution=Lloyd%3A3&mask=%7B%22shape%22%3A%7B%22type%22%3A%22Hexagon%22%7D%7D\
+             &unknown=1#top",
+        ).unwrap();
+        let options = MapOptions {
+            size: Size::new(800, 600),
+            template: Some(Template::Atoll),
+            point_distribution: PointDistribution::Lloyd(3),
+            mask: Some(Mask::new(MaskShape::Hexagon, MaskMode::Ocean)),
+            ..MapOptions::default()
+        };
+        assert_eq!(link, Permalink::new(7, options));
+    }
+
+    #[test]
+    fn rejects_bad_links() {
+        assert_eq!(Permalink::parse("width=800"), Err(PermalinkError::MissingSeed));
+        assert_eq!(
+            Permalink::parse("seed=1&cells=many"),
+            Err(PermalinkError::InvalidValue {
+                key: "cells".to_owned(),
+                value: "many".to_owned(),
+            }),
+        );
+    }
+
+    #[test]
+    fn density_becomes_cells() {
+        let link = Permalink::parse("seed=1&density=3").unwrap();
+        assert_eq!(link.options.cells, 30_000);
+    }
+
+    #[test]
+    fn cells_take_precedence_over_density() {
+        let link = Permalink::parse("seed=1&cells=20000&density=3").unwrap();
+        assert_eq!(link.options.cells, 20_000);
+        let link = Permalink::parse("seed=1&density=3&cells=20000").unwrap();
+        assert_eq!(link.options.cells, 20_000);
+    }
+}
@@ -0,0 +1,399 @@
+use crate::{Grid, Map, MapError, MapOptions};
+use crate::heightmap::HeightmapGenerator;
+use crate::image::GrayImage;
+use crate::pack::PackGrid;
+use crate::progress::{NoProgress, Progress, Stopwatch};
+use crate::rng;
+use crate::{time_end, time_start};
+
+/// A single step of map generation.
+///
+/// Stages run in order over the same `Map`, each reading what earlier stages
+/// produced and filling in its own layer. Randomness should come from
+/// `Map::rng` with a label of the stage's own, so stages stay independent of
+/// each other.
+pub trait Stage {
+    /// Name the stage is addressed by in a `Pipeline`.
+    fn name(&self) -> &str;
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError>;
+}
+
+/// Places the points and builds the voronoi graph.
+pub struct GridStage;
+
+impl Stage for GridStage {
+    fn name(&self) -> &str {
+        "grid"
+    }
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError> {
+        let mut rng = map.rng(rng::POINTS);
+        let options = &map.options;
+        map.grid = Grid::new(
+            options.size,
+            options.cells,
+            options.point_distribution,
+            options.topology,
+            &mut rng,
+            &map.cancel,
+        )?;
+        Ok(())
+    }
+}
+
+/// Raises the terrain from the template in the options, or from one picked at
+/// random if there is none.
+pub struct HeightmapStage;
+
+impl Stage for HeightmapStage {
+    fn name(&self) -> &str {
+        "heightmap"
+    }
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError> {
+        time_start!("generate_hightmap");
+        let mut rng = map.rng(rng::HEIGHTMAP);
+        let options = &map.options;
+        let template = match options.template {
+            Some(template) => {
+                HeightmapGenerator::generate_with_template(&mut map.grid, &mut rng, template, &map.cancel)?;
+                template
+            }
+            None => HeightmapGenerator::generate(&mut map.grid, &mut rng, &options.template_weights, &map.cancel)?,
+        };
+        map.template = Some(template);
+        time_end!("generate_hightmap");
+        Ok(())
+    }
+}
+
+/// Wears down the terrain with the erosion in the options. Does nothing unless
+/// they ask for some iterations.
+pub struct ErosionStage;
+
+impl Stage for ErosionStage {
+    fn name(&self) -> &str {
+        "erosion"
+    }
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError> {
+        let mut rng = map.rng(rng::EROSION);
+        map.options.erosion.apply(&mut map.grid, &mut rng, &map.cancel)
+    }
+}
+
+/// Takes the terrain from a grayscale image instead of a template. Replace the
+/// `HeightmapStage` with it to import a heightmap, see
+/// `HeightmapGenerator::generate_from_image`.
+pub struct ImageHeightmapStage {
+    image: GrayImage,
+    sea_level: f32,
+}
+
+impl ImageHeightmapStage {
+    pub fn new(image: GrayImage, sea_level: f32) -> Self {
+        ImageHeightmapStage {
+            image,
+            sea_level,
+        }
+    }
+}
+
+impl Stage for ImageHeightmapStage {
+    fn name(&self) -> &str {
+        "heightmap"
+    }
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError> {
+        time_start!("import_heightmap");
+        HeightmapGenerator::generate_from_image(&mut map.grid, &self.image, self.sea_level);
+        map.template = None;
+        time_end!("import_heightmap");
+        Ok(())
+    }
+}
+
+/// Applies the mask in the options, if there is one, to the heightmap.
+pub struct MaskStage;
+
+impl Stage for MaskStage {
+    fn name(&self) -> &str {
+        "mask"
+    }
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError> {
+        if let Some(mask) = &map.options.mask {
+            mask.apply(&mut map.grid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups cells into islands, oceans and lakes and marks the coasts.
+pub struct FeaturesStage;
+
+impl Stage for FeaturesStage {
+    fn name(&self) -> &str {
+        "features"
+    }
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError> {
+        map.grid.mark_features(&map.cancel)?;
+        // TODO: open near sea lakes
+        Ok(())
+    }
+}
+
+/// Builds the packed graph over the land. Comes after the features, as it
+/// needs the coasts.
+pub struct PackStage;
+
+impl Stage for PackStage {
+    fn name(&self) -> &str {
+        "pack"
+    }
+
+    fn run(&mut self, map: &mut Map) -> Result<(), MapError> {
+        map.pack = PackGrid::new(&map.grid)?;
+        Ok(())
+    }
+}
+
+/// An ordered list of named stages that together generate a map.
+///
+/// `Pipeline::default()` holds the stages `Map::generate_with_seed` runs.
+/// Stages can be inserted, replaced or removed by name before running.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .push(GridStage)
+            .push(HeightmapStage)
+            .push(ErosionStage)
+            .push(MaskStage)
+            .push(FeaturesStage);
+        // TODO: calculate map coords
+        // TODO: calculate temperatures
+        // TODO: generate precipitation
+        pipeline.push(PackStage);
+
+        // TODO: elevate lakes
+        // TODO: generate rivers
+        // TODO: define biomes
+
+        // TODO: rank cells?
+        // TODO: populate cultures
+        // TODO: expand cultures
+        // TODO: generate burgs and states
+        // TODO: generate religions
+
+        // TODO: add zone?
+        // TODO: add markers
+        pipeline
+    }
+}
+
+impl Pipeline {
+    /// A pipeline without any stages.
+    pub fn new() -> Self {
+        Pipeline {
+            stages: Vec::new(),
+        }
+    }
+
+    pub fn stage_names(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|s| s.name())
+    }
+
+    fn position(&self, name: &str) -> Result<usize, MapError> {
+        self.stages
+            .iter()
+            .position(|s| s.name() == name)
+            .ok_or_else(|| MapError::UnknownStage(name.to_owned()))
+    }
+
+    /// Add a stage to the end of the pipeline.
+    pub fn push<S: Stage + 'static>(&mut self, stage: S) -> &mut Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn insert_before<S: Stage + 'static>(
+        &mut self,
+        name: &str,
+        stage: S,
+    ) -> Result<&mut Self, MapError> {
+        let i = self.position(name)?;
+        self.stages.insert(i, Box::new(stage));
+        Ok(self)
+    }
+
+    pub fn insert_after<S: Stage + 'static>(
+        &mut self,
+        name: &str,
+        stage: S,
+    ) -> Result<&mut Self, MapError> {
+        let i = self.position(name)?;
+        self.stages.insert(i + 1, Box::new(stage));
+        Ok(self)
+    }
+
+    /// Swap out the stage called `name`. The new stage keeps its own name.
+    pub fn replace<S: Stage + 'static>(
+        &mut self,
+        name: &str,
+        stage: S,
+    ) -> Result<&mut Self, MapError> {
+        let i = self.position(name)?;
+        self.stages[i] = Box::new(stage);
+        Ok(self)
+    }
+
+    /// Remove the stage called `name` so it is skipped.
+    pub fn remove(&mut self, name: &str) -> Result<&mut Self, MapError> {
+        let i = self.position(name)?;
+        self.stages.remove(i);
+        Ok(self)
+    }
+
+    /// Generate a new map by running every stage.
+    pub fn run(&mut self, options: MapOptions, seed: u64) -> Result<Map, MapError> {
+        let mut map = Map::new(options, seed);
+        self.run_on(&mut map, &mut NoProgress)?;
+        Ok(map)
+    }
+
+    /// Run every stage over `map`, reporting each finished one to `progress`.
+    /// Stops before the next stage once `map.cancel` is cancelled.
+    pub fn run_on(&mut self, map: &mut Map, progress: &mut dyn Progress) -> Result<(), MapError> {
+        self.run_stages(map, 0, progress)
+    }
+
+    /// Run the stage called `name` and all stages after it over an existing
+    /// map, e.g. to regenerate the terrain without rebuilding the grid.
+    pub fn run_from(&mut self, map: &mut Map, name: &str) -> Result<(), MapError> {
+        let i = self.position(name)?;
+        self.run_stages(map, i, &mut NoProgress)
+    }
+
+    fn run_stages(
+        &mut self,
+        map: &mut Map,
+        first: usize,
+        progress: &mut dyn Progress,
+    ) -> Result<(), MapError> {
+        let timer = Stopwatch::start();
+        let total = self.stages.len() - first;
+        for (i, stage) in self.stages[first..].iter_mut().enumerate() {
+            map.cancel.check()?;
+            stage.run(map)?;
+            progress.report(stage.name(), (i + 1) as f32 / total as f32, timer.elapsed());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    // Writes its name to a log shared with the test when run.
+    struct Record {
+        name: &'static str,
+        log: Rc<RefCell<Vec<&'static str>>>,
+    }
+
+    impl Stage for Record {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn run(&mut self, _: &mut Map) -> Result<(), MapError> {
+            self.log.borrow_mut().push(self.name);
+            Ok(())
+        }
+    }
+
+    fn recording(names: &[&'static str]) -> (Pipeline, Rc<RefCell<Vec<&'static str>>>) {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut pipeline = Pipeline::new();
+        for &name in names {
+            pipeline.push(Record { name, log: log.clone() });
+        }
+        (pipeline, log)
+    }
+
+    #[test]
+    fn default_stages() {
+        let pipeline = Pipeline::default();
+        let names: Vec<_> = pipeline.stage_names().collect();
+        assert_eq!(names, ["grid", "heightmap", "erosion", "mask", "features", "pack"]);
+    }
+
+    #[test]
+    fn edits_stages_by_name() {
+        let (mut pipeline, log) = recording(&["a", "b", "c"]);
+        pipeline
+            .insert_before("a", Record { name: "first", log: log.clone() }).unwrap()
+            .insert_after("c", Record { name: "last", log: log.clone() }).unwrap()
+            .replace("b", Record { name: "new b", log: log.clone() }).unwrap()
+            .remove("c").unwrap();
+        assert_eq!(pipeline.stage_names().collect::<Vec<_>>(), ["first", "a", "new b", "last"]);
+
+        pipeline.run(MapOptions::default(), 1).unwrap();
+        assert_eq!(*log.borrow(), ["first", "a", "new b", "last"]);
+    }
+
+    #[test]
+    fn unknown_stages_are_errors() {
+        let (mut pipeline, log) = recording(&["a"]);
+        let results = vec![
+            pipeline.insert_before("b", Record { name: "x", log: log.clone() }).map(|_| ()),
+            pipeline.insert_after("b", Record { name: "x", log: log.clone() }).map(|_| ()),
+            pipeline.replace("b", Record { name: "x", log: log.clone() }).map(|_| ()),
+            pipeline.remove("b").map(|_| ()),
+            pipeline.run_from(&mut Map::new(MapOptions::default(), 1), "b"),
+        ];
+        for result in results {
+            match result {
+                Err(MapError::UnknownStage(name)) => assert_eq!(name, "b"),
+                other => panic!("expected an unknown stage, got {:?}", other),
+            }
+        }
+        assert_eq!(pipeline.stage_names().collect::<Vec<_>>(), ["a"]);
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn runs_from_a_stage_and_reports_progress() {
+        let (mut pipeline, log) = recording(&["a", "b", "c"]);
+        let mut map = Map::new(MapOptions::default(), 1);
+        pipeline.run_from(&mut map, "b").unwrap();
+        assert_eq!(*log.borrow(), ["b", "c"]);
+
+        let mut reports = Vec::new();
+        let mut progress = |stage: &str, done: f32, _| reports.push((stage.to_owned(), done));
+        pipeline.run_on(&mut map, &mut progress).unwrap();
+        assert_eq!(reports, [("a".to_owned(), 1.0 / 3.0), ("b".to_owned(), 2.0 / 3.0), ("c".to_owned(), 1.0)]);
+    }
+
+    #[test]
+    fn stops_once_cancelled() {
+        let (mut pipeline, log) = recording(&["a", "b"]);
+        let mut map = Map::new(MapOptions::default(), 1);
+        map.cancel.cancel();
+        match pipeline.run_on(&mut map, &mut NoProgress) {
+            Err(MapError::Cancelled) => {}
+            other => panic!("expected cancellation, got {:?}", other),
+        }
+        assert!(log.borrow().is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+//! Placement of the grid points.
+
+use std::f32::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as _;
+use triangulation::Point;
+
+use crate::{CancelToken, MapError, Size, Topology};
+use crate::util::FloatExt;
+
+/// How the grid points are spread over the map.
+///
+/// Serialized as a string such as `"PoissonDisc"` or `"Lloyd:2"`, as TOML has
+/// no representation for enum variants with data.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum PointDistribution {
+    /// A square lattice with every point moved randomly within its square.
+    Jittered,
+    /// Points at random, but never closer to each other than a minimum
+    /// distance.
+    PoissonDisc,
+    /// Jittered points, each moved to the centroid of its cell the given
+    /// number of times.
+    Lloyd(u8),
+}
+
+impl Default for PointDistribution {
+    fn default() -> Self {
+        PointDistribution::Jittered
+    }
+}
+
+impl fmt::Display for PointDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PointDistribution::Jittered => write!(f, "Jittered"),
+            PointDistribution::PoissonDisc => write!(f, "Poisson Disc"),
+            PointDistribution::Lloyd(iterations) => write!(f, "Lloyd ({} iterations)", iterations),
+        }
+    }
+}
+
+impl Serialize for PointDistribution {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            PointDistribution::Jittered => serializer.serialize_str("Jittered"),
+            PointDistribution::PoissonDisc => serializer.serialize_str("PoissonDisc"),
+            PointDistribution::Lloyd(iterations) =>
+                serializer.serialize_str(&format!("Lloyd:{}", iterations)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PointDistribution {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(D::Error::custom)
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParsePointDistributionError(String);
+
+impl fmt::Display for ParsePointDistributionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "unknown point distribution \"{}\", expected jittered, poisson or lloyd[:ITERATIONS]",
+            self.0,
+        )
+    }
+}
+
+impl std::error::Error for ParsePointDistributionError {}
+
+impl FromStr for PointDistribution {
+    type Err = ParsePointDistributionError;
+
+    /// Parse a distribution case-insensitively. Lloyd relaxation takes an
+    /// optional iteration count, e.g. `lloyd:3`, and defaults to 2.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || ParsePointDistributionError(s.to_owned());
+        let normalized: String = s
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-' && *c != '_')
+            .flat_map(char::to_lowercase)
+            .collect();
+        match normalized.as_str() {
+            "jittered" => Ok(PointDistribution::Jittered),
+            "poisson" | "poissondisc" => Ok(PointDistribution::PoissonDisc),
+            "lloyd" => Ok(PointDistribution::Lloyd(2)),
+            _ if normalized.starts_with("lloyd:") => normalized["lloyd:".len()..]
+                .parse()
+                .map(PointDistribution::Lloyd)
+                .map_err(|_| err()),
+            _ => Err(err()),
+        }
+    }
+}
+
+// Ratio of the Poisson-disc minimum distance to the lattice spacing. Bridson's
+// algorithm fills the plane to about one point per 1.6 r², so this gives about
+// as many points as the jittered lattice.
+const POISSON_RADIUS: f32 = 0.8;
+// Candidates tried around an active point before it is retired.
+const POISSON_TRIES: u32 = 30;
+
+/// Bridson's Poisson-disc sampling over the map, with points about `spacing`
+/// apart.
+pub(crate) fn poisson_disc(size: Size, spacing: f32, rng: &mut StdRng) -> Vec<Point> {
+    let radius = spacing * POISSON_RADIUS;
+    let width = size.width as f32;
+    let height = size.height as f32;
+
+    // Background grid with at most one point per bucket.
+    let bucket_size = radius / 2f32.sqrt();
+    let buckets_x = (width / bucket_size).ceil() as usize;
+    let buckets_y = (height / bucket_size).ceil() as usize;
+    let mut buckets: Vec<Option<usize>> = vec![None; buckets_x * buckets_y];
+    let bucket = |p: Point| {
+        let x = ((p.x / bucket_size) as usize).min(buckets_x - 1);
+        let y = ((p.y / bucket_size) as usize).min(buckets_y - 1);
+        (x, y)
+    };
+
+    let mut points = Vec::new();
+    let mut active = Vec::new();
+
+    let first = Point::new(rng.gen_range(0.0, width), rng.gen_range(0.0, height));
+    let (x, y) = bucket(first);
+    buckets[y * buckets_x + x] = Some(0);
+    points.push(first);
+    active.push(0);
+
+    while !active.is_empty() {
+        let a = rng.gen_range(0, active.len());
+        let origin = points[active[a]];
+
+        let mut placed = false;
+        for _ in 0..POISSON_TRIES {
+            let angle = rng.gen_range(0.0, 2.0 * PI);
+            let distance = rng.gen_range(radius, 2.0 * radius);
+            let candidate = Point::new(
+                (origin.x + angle.cos() * distance).round_decimals(2),
+                (origin.y + angle.sin() * distance).round_decimals(2),
+            );
+            if candidate.x < 0.0 || candidate.x >= width || candidate.y < 0.0 || candidate.y >= height {
+                continue;
+            }
+
+            let (cx, cy) = bucket(candidate);
+            let too_close = (cy.saturating_sub(2)..(cy + 3).min(buckets_y))
+                .flat_map(|y| (cx.saturating_sub(2)..(cx + 3).min(buckets_x)).map(move |x| (x, y)))
+                .filter_map(|(x, y)| buckets[y * buckets_x + x])
+                .any(|i| {
+                    let p = points[i];
+                    (p.x - candidate.x).powi(2) + (p.y - candidate.y).powi(2) < radius * radius
+                });
+            if too_close {
+                continue;
+            }
+
+            buckets[cy * buckets_x + cx] = Some(points.len());
+            active.push(points.len());
+            points.push(candidate);
+            placed = true;
+            break;
+        }
+
+        if !placed {
+            active.swap_remove(a);
+        }
+    }
+
+    points
+}
+
+/// Move every point to the centroid of its voronoi cell, `iterations` times.
+/// The boundary points stay where they are.
+pub(crate) fn lloyd_relax(
+    size: Size,
+    topology: Topology,
+    boundary: &[Point],
+    mut points: Vec<Point>,
+    iterations: u8,
+    cancel: &CancelToken,
+) -> Result<Vec<Point>, MapError> {
+    let width = size.width as f32;
+    let height = size.height as f32;
+
+    for _ in 0..iterations {
+        cancel.check()?;
+        let voronoi = topology.voronoi(size, &points, boundary)?;
+
+        for cell in voronoi.cells() {
+            let centroid = voronoi.cell(cell).centroid;
+            // Rounding can take a centroid right at the seam across it.
+            let x = centroid.x.round_decimals(2);
+            let x = if topology.wraps_horizontally() {
+                x.rem_euclid(width)
+            } else {
+                x.clamp(0.0, width)
+            };
+            points[cell.as_usize()] = Point::new(x, centroid.y.round_decimals(2).clamp(0.0, height));
+        }
+    }
+
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::Grid;
+
+    #[test]
+    fn relaxed_points_stay_on_the_map() {
+        let size = Size::new(300, 200);
+        for &topology in &[Topology::Flat, Topology::Cylinder] {
+            let mut rng = StdRng::seed_from_u64(9);
+            let grid = Grid::new(
+                size,
+                crate::MIN_CELLS,
+                PointDistribution::Lloyd(3),
+                topology,
+                &mut rng,
+                &CancelToken::new(),
+            ).unwrap();
+
+            for p in &grid.points {
+                // The right edge of a cylinder is its left edge.
+                let x_on_map = if topology == Topology::Cylinder { p.x < 300.0 } else { p.x <= 300.0 };
+                assert!(p.x >= 0.0 && x_on_map && (0.0..=200.0).contains(&p.y), "{:?} on a {} map", p, topology);
+            }
+        }
+    }
+}
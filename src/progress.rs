@@ -0,0 +1,116 @@
+//! Progress reporting and cancellation of map generation.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::MapError;
+
+/// Observer of a running `Pipeline`.
+///
+/// Closures taking the same arguments as `report` can be used directly.
+pub trait Progress {
+    /// Called after each stage with its name, the fraction of stages done so
+    /// far and the time elapsed since generation started.
+    fn report(&mut self, stage: &str, done: f32, elapsed: Duration);
+}
+
+impl<F: FnMut(&str, f32, Duration)> Progress for F {
+    fn report(&mut self, stage: &str, done: f32, elapsed: Duration) {
+        self(stage, done, elapsed)
+    }
+}
+
+/// A `Progress` that ignores every report.
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn report(&mut self, stage: &str, done: f32, elapsed: Duration) {}
+}
+
+/// Shared flag to abort generation or drawing early.
+///
+/// Clones refer to the same flag, so one clone can be handed to the map while
+/// another is kept to cancel it. Work that is cancelled returns
+/// `MapError::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// `Err(MapError::Cancelled)` once `cancel` was called.
+    pub fn check(&self) -> Result<(), MapError> {
+        if self.is_cancelled() {
+            Err(MapError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Measures elapsed time. `std::time::Instant` is not available on wasm, so
+/// the page's clock is used there.
+pub(crate) struct Stopwatch {
+    #[cfg(target_arch = "wasm32")]
+    start: f64,
+    #[cfg(not(target_arch = "wasm32"))]
+    start: std::time::Instant,
+}
+
+impl Stopwatch {
+    #[cfg(target_arch = "wasm32")]
+    pub fn start() -> Self {
+        Stopwatch {
+            start: js_sys::Date::now(),
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn start() -> Self {
+        Stopwatch {
+            start: std::time::Instant::now(),
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64((js_sys::Date::now() - self.start).max(0.0) / 1000.0)
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+thread_local! {
+    static TIMERS: RefCell<HashMap<String, Stopwatch>> = RefCell::new(HashMap::new());
+}
+
+// Native side of `time_start!`, mirroring `console.time`.
+#[doc(hidden)]
+pub fn timer_start(label: &str) {
+    TIMERS.with(|timers| timers.borrow_mut().insert(label.to_owned(), Stopwatch::start()));
+}
+
+// Native side of `time_end!`, printing the time like `console.timeEnd`.
+#[doc(hidden)]
+pub fn timer_end(label: &str) {
+    match TIMERS.with(|timers| timers.borrow_mut().remove(label)) {
+        Some(timer) => eprintln!("{}: {:.3}ms", label, timer.elapsed().as_secs_f64() * 1000.0),
+        None => eprintln!("Timer '{}' does not exist", label),
+    }
+}
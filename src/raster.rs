@@ -0,0 +1,282 @@
+//! Rasterizing the heightmap into a grayscale image, e.g. for the terrain
+//! tools of game engines.
+
+use std::fmt;
+use std::str::FromStr;
+
+use triangulation::Point;
+
+use crate::{Grid, MapError, Size};
+use crate::heightmap::WORLD_MAX;
+use crate::image::GrayImage;
+use crate::topology;
+use crate::voronoi::CellIndex;
+use crate::{time_end, time_start};
+
+// How far outside of a triangle, in barycentric weight, a pixel center may be
+// and still be filled from it, so that no pixels slip between triangles.
+const EDGE_TOLERANCE: f32 = 1e-4;
+
+/// How the heights between the grid points are filled in.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Sampling {
+    /// Each pixel takes the height of the cell it is in, giving flat
+    /// terraces.
+    Nearest,
+    /// Heights are interpolated linearly across the Delaunay triangles
+    /// between the cell centers.
+    Linear,
+}
+
+impl fmt::Display for Sampling {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Sampling::Nearest => write!(f, "nearest"),
+            Sampling::Linear => write!(f, "linear"),
+        }
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nearest" => Ok(Sampling::Nearest),
+            "linear" => Ok(Sampling::Linear),
+            _ => Err(format!("unknown sampling \"{}\", expected nearest or linear", s)),
+        }
+    }
+}
+
+/// Bits per pixel of a rasterized heightmap.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+impl BitDepth {
+    pub fn max_value(self) -> u16 {
+        match self {
+            BitDepth::Eight => u8::max_value() as u16,
+            BitDepth::Sixteen => u16::max_value(),
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(BitDepth::Eight),
+            "16" => Ok(BitDepth::Sixteen),
+            _ => Err(format!("unsupported bit depth \"{}\", expected 8 or 16", s)),
+        }
+    }
+}
+
+/// Rasterize the heights of `grid` into an image of `size` stretched over the
+/// map. A height of 0 becomes black and `WORLD_MAX` white.
+pub fn heightmap_image(
+    grid: &Grid,
+    size: Size,
+    sampling: Sampling,
+    depth: BitDepth,
+) -> Result<GrayImage, MapError> {
+    if size.width == 0 || size.height == 0 {
+        return Err(MapError::InvalidSize(size));
+    }
+    time_start!("rasterize_heightmap");
+
+    // Pixels left unset are filled in from the nearest cell.
+    let mut heights = vec![None; size.width as usize * size.height as usize];
+    if sampling == Sampling::Linear {
+        interpolate_triangles(grid, size, &mut heights);
+    }
+    let scale_x = grid.size.width as f32 / size.width as f32;
+    let scale_y = grid.size.height as f32 / size.height as f32;
+    let max_value = depth.max_value();
+    let pixels = heights
+        .iter()
+        .enumerate()
+        .map(|(i, height)| {
+            let height = height.unwrap_or_else(|| {
+                let x = ((i % size.width as usize) as f32 + 0.5) * scale_x;
+                let y = ((i / size.width as usize) as f32 + 0.5) * scale_y;
+                grid.heights[grid.coords_to_cell_index(x, y).as_usize()]
+            });
+            (height / WORLD_MAX * max_value as f32).round().max(0.0).min(max_value as f32) as u16
+        })
+        .collect();
+
+    time_end!("rasterize_heightmap");
+    Ok(GrayImage::new(size.width, size.height, max_value, pixels)?)
+}
+
+// Fill in the pixels covered by the Delaunay triangles, interpolating the
+// heights of their corners. The boundary points have no height, corners on
+// them take the mean of the others.
+fn interpolate_triangles(grid: &Grid, size: Size, heights: &mut [Option<f32>]) {
+    let cells = grid.voronoi.cell_count();
+    let map_width = grid.size.width as f32;
+    let scale_x = size.width as f32 / map_width;
+    let scale_y = size.height as f32 / grid.size.height as f32;
+    let position = |c: CellIndex| {
+        let i = c.as_usize();
+        if i < grid.points.len() { grid.points[i] } else { grid.boundary[i - grid.points.len()] }
+    };
+    // Triangles across the seam are unwrapped to one side and drawn on both.
+    let shifts: &[f32] = if grid.topology.wraps_horizontally() {
+        &[-map_width, 0.0, map_width]
+    } else {
+        &[0.0]
+    };
+
+    for v in 0..grid.voronoi.vertex_count() {
+        let corners = grid.voronoi.vertex(v.into()).connected_cells;
+        let known: Vec<_> = corners
+            .iter()
+            .filter(|c| c.as_usize() < cells)
+            .map(|c| grid.heights[c.as_usize()])
+            .collect();
+        if known.is_empty() {
+            continue;
+        }
+        let mean = known.iter().sum::<f32>() / known.len() as f32;
+        let mut h = [mean; 3];
+        let mut p = [Point::new(0.0, 0.0); 3];
+        for k in 0..3 {
+            if corners[k].as_usize() < cells {
+                h[k] = grid.heights[corners[k].as_usize()];
+            }
+            p[k] = position(corners[k]);
+            if k > 0 && grid.topology.wraps_horizontally() {
+                p[k].x = p[0].x + topology::wrapped_dx(p[k].x - p[0].x, map_width);
+            }
+        }
+
+        for &shift in shifts {
+            let [a, b, c] = [
+                Point::new((p[0].x + shift) * scale_x, p[0].y * scale_y),
+                Point::new((p[1].x + shift) * scale_x, p[1].y * scale_y),
+                Point::new((p[2].x + shift) * scale_x, p[2].y * scale_y),
+            ];
+            let det = (b.y - c.y) * (a.x - c.x) + (c.x - b.x) * (a.y - c.y);
+            if det.abs() < std::f32::EPSILON {
+                continue;
+            }
+            let pixel_range = |min: f32, max: f32, length: u32| {
+                let start = (min - 0.5).ceil().max(0.0) as u32;
+                let end = ((max - 0.5).floor() + 1.0).max(0.0).min(length as f32) as u32;
+                start..end
+            };
+            let xs = pixel_range(a.x.min(b.x).min(c.x), a.x.max(b.x).max(c.x), size.width);
+            let ys = pixel_range(a.y.min(b.y).min(c.y), a.y.max(b.y).max(c.y), size.height);
+            for py in ys {
+                for px in xs.clone() {
+                    let (x, y) = (px as f32 + 0.5, py as f32 + 0.5);
+                    let wa = ((b.y - c.y) * (x - c.x) + (c.x - b.x) * (y - c.y)) / det;
+                    let wb = ((c.y - a.y) * (x - c.x) + (a.x - c.x) * (y - c.y)) / det;
+                    let wc = 1.0 - wa - wb;
+                    if wa < -EDGE_TOLERANCE || wb < -EDGE_TOLERANCE || wc < -EDGE_TOLERANCE {
+                        continue;
+                    }
+                    heights[(py * size.width + px) as usize] = Some(wa * h[0] + wb * h[1] + wc * h[2]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+    use crate::{CancelToken, PointDistribution, Topology};
+
+    // A grid with heights rising from 0 at the left edge to `WORLD_MAX` at
+    // the right.
+    fn slope(topology: Topology) -> Grid {
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut grid = Grid::new(
+            Size::new(300, 200),
+            crate::MIN_CELLS,
+            PointDistribution::Jittered,
+            topology,
+            &mut rng,
+            &CancelToken::new(),
+        ).unwrap();
+        grid.heights = grid
+            .points
+            .iter()
+            .take(grid.voronoi.cell_count())
+            .map(|p| p.x / 300.0 * WORLD_MAX)
+            .collect();
+        grid
+    }
+
+    #[test]
+    fn nearest_takes_the_height_of_the_cell() {
+        let grid = slope(Topology::Flat);
+        let image = heightmap_image(&grid, Size::new(150, 100), Sampling::Nearest, BitDepth::Sixteen).unwrap();
+        assert_eq!((image.width(), image.height(), image.max_value()), (150, 100, 65535));
+        for (i, &pixel) in image.pixels().iter().enumerate() {
+            let (x, y) = ((i % 150) as f32 * 2.0 + 1.0, (i / 150) as f32 * 2.0 + 1.0);
+            let height = grid.heights[grid.coords_to_cell_index(x, y).as_usize()];
+            assert_eq!(pixel, (height / WORLD_MAX * 65535.0).round() as u16, "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn linear_follows_the_slope() {
+        let grid = slope(Topology::Flat);
+        let image = heightmap_image(&grid, Size::new(300, 200), Sampling::Linear, BitDepth::Eight).unwrap();
+        assert_eq!(image.max_value(), 255);
+        // Triangles on the boundary take made up heights, so only the middle
+        // is exact.
+        for y in 20..180 {
+            for x in 20..280 {
+                let expected = (x as f32 + 0.5) / 300.0 * 255.0;
+                let pixel = image.pixels()[y * 300 + x] as f32;
+                assert!((pixel - expected).abs() <= 1.0, "({}, {}) is {} not {}", x, y, pixel, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn linear_fills_both_sides_of_the_seam() {
+        let mut grid = slope(Topology::Cylinder);
+        grid.heights = vec![60.0; grid.voronoi.cell_count()];
+        let size = Size::new(300, 200);
+        let mut heights = vec![None; 300 * 200];
+        interpolate_triangles(&grid, size, &mut heights);
+        for y in 20..180 {
+            for &x in &[0, 1, 298, 299] {
+                let height = heights[y * 300 + x].unwrap_or_else(|| panic!("({}, {}) is not filled", x, y));
+                assert!((height - 60.0).abs() < 1e-3, "({}, {}) is {}", x, y, height);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_empty_images() {
+        let grid = slope(Topology::Flat);
+        match heightmap_image(&grid, Size::new(0, 100), Sampling::Nearest, BitDepth::Eight) {
+            Err(MapError::InvalidSize(_)) => {}
+            other => panic!("expected an invalid size, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parses_sampling_and_depth() {
+        assert_eq!("Linear".parse(), Ok(Sampling::Linear));
+        assert_eq!("nearest".parse(), Ok(Sampling::Nearest));
+        assert!("cubic".parse::<Sampling>().is_err());
+        assert_eq!("16".parse(), Ok(BitDepth::Sixteen));
+        assert!("12".parse::<BitDepth>().is_err());
+    }
+}
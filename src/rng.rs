@@ -0,0 +1,33 @@
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+// Labels of the RNG streams used by the built-in stages.
+pub const POINTS: &str = "points";
+pub const HEIGHTMAP: &str = "heightmap";
+pub const EROSION: &str = "erosion";
+pub const FEATURES: &str = "features";
+pub const CLIMATE: &str = "climate";
+pub const CULTURE: &str = "culture";
+
+/// Derive an independent RNG for a stage from the map seed.
+///
+/// Each stream depends only on the seed, the stage label and how often that
+/// stage was rerolled, so extra draws in one stage never shift another. The
+/// label is hashed with FNV-1a rather than `DefaultHasher`, whose output may
+/// change between Rust releases.
+pub fn stage_rng(seed: u64, label: &str, reroll: u32) -> StdRng {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in label.bytes() {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    let stream = splitmix64(seed ^ hash) ^ splitmix64(reroll as u64);
+    StdRng::seed_from_u64(stream)
+}
+
+fn splitmix64(v: u64) -> u64 {
+    let mut z = v.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
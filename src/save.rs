@@ -0,0 +1,386 @@
+//! Versioned binary save format for complete maps.
+//!
+//! A save file is the magic bytes `FMGMAP`, the format version as a
+//! little-endian `u16` and the map as CBOR. Only the data that can't be
+//! recomputed is stored: the voronoi graph is rebuilt from the points on load,
+//! while heights and all other layers are kept as they are, manual edits
+//! included.
+//!
+//! CBOR keeps field names, so adding a field with `#[serde(default)]` to any
+//! of the saved types does not need a new version. Anything else, such as a
+//! changed meaning or type of a field, bumps `FORMAT_VERSION` and adds a
+//! migration from the previous version to `decode`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+use triangulation::Point;
+
+use crate::{CancelToken, Coast, Feature, Grid, Map, MapError, MapOptions};
+use crate::heightmap::Template;
+use crate::options::LegacyOptions;
+use crate::pack::PackGrid;
+
+const MAGIC: &[u8; 6] = b"FMGMAP";
+
+/// Version of the save format written by `Map::save`.
+pub const FORMAT_VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(io::Error),
+    Encoding(serde_cbor::Error),
+    /// The data does not start with the save file magic bytes.
+    NotASave,
+    /// The save was written by a newer version of the crate.
+    UnsupportedVersion(u16),
+    /// The layers do not match the number of cells, or refer to features
+    /// that are missing.
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "{}", e),
+            SaveError::Encoding(e) => write!(f, "invalid map data: {}", e),
+            SaveError::NotASave => write!(f, "not a saved map"),
+            SaveError::UnsupportedVersion(v) => write!(
+                f,
+                "map format version {} is newer than the supported version {}",
+                v,
+                FORMAT_VERSION,
+            ),
+            SaveError::Corrupt(layer) => write!(f, "corrupt map: invalid {}", layer),
+        }
+    }
+}
+
+impl std::error::Error for SaveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SaveError::Io(e) => Some(e),
+            SaveError::Encoding(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+impl From<serde_cbor::Error> for SaveError {
+    fn from(e: serde_cbor::Error) -> Self {
+        SaveError::Encoding(e)
+    }
+}
+
+// Layout of `FORMAT_VERSION` 2. Version 1 had the same layout with whole
+// heights, `SavedMap<u8>`.
+#[derive(Serialize, Deserialize)]
+struct SavedMap<H = f32> {
+    seed: u64,
+    options: MapOptions,
+    rerolls: BTreeMap<String, u32>,
+    // Missing from maps saved before templates were picked at random, which
+    // were generated from the template in their options.
+    #[serde(default)]
+    template: Option<Template>,
+    grid: SavedGrid<H>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedGrid<H = f32> {
+    point_spacing: f32,
+    boundary: Vec<[f32; 2]>,
+    points: Vec<[f32; 2]>,
+    heights: Vec<H>,
+    feature_map: Vec<Option<usize>>,
+    features: Vec<Feature>,
+    coasts: Vec<Coast>,
+}
+
+// Options of version 1 saves written before cell counts have a density
+// instead.
+#[derive(Deserialize)]
+struct LegacySavedMap {
+    options: LegacyOptions,
+}
+
+fn to_pairs(points: &[Point]) -> Vec<[f32; 2]> {
+    points.iter().map(|p| [p.x, p.y]).collect()
+}
+
+fn from_pairs(points: Vec<[f32; 2]>) -> Vec<Point> {
+    points.into_iter().map(|[x, y]| Point::new(x, y)).collect()
+}
+
+fn decode(version: u16, payload: &[u8]) -> Result<SavedMap, SaveError> {
+    match version {
+        1 => {
+            let mut saved = migrate_whole_heights(serde_cbor::from_slice(payload)?);
+            let legacy: LegacySavedMap = serde_cbor::from_slice(payload)?;
+            legacy.options.migrate(&mut saved.options);
+            Ok(saved)
+        }
+        2 => Ok(serde_cbor::from_slice(payload)?),
+        v => Err(SaveError::UnsupportedVersion(v)),
+    }
+}
+
+// Version 1 to 2: heights became `f32` on the same scale.
+fn migrate_whole_heights(saved: SavedMap<u8>) -> SavedMap {
+    let grid = saved.grid;
+    SavedMap {
+        seed: saved.seed,
+        options: saved.options,
+        rerolls: saved.rerolls,
+        template: saved.template,
+        grid: SavedGrid {
+            point_spacing: grid.point_spacing,
+            boundary: grid.boundary,
+            points: grid.points,
+            heights: grid.heights.into_iter().map(f32::from).collect(),
+            feature_map: grid.feature_map,
+            features: grid.features,
+            coasts: grid.coasts,
+        },
+    }
+}
+
+fn write_saved<W: Write>(mut writer: W, saved: &SavedMap) -> Result<(), SaveError> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    serde_cbor::to_writer(&mut writer, saved)?;
+    Ok(())
+}
+
+fn read_saved<R: Read>(mut reader: R) -> Result<SavedMap, SaveError> {
+    let mut magic = [0; 6];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SaveError::NotASave);
+    }
+    let mut version = [0; 2];
+    reader.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+    let mut payload = Vec::new();
+    reader.read_to_end(&mut payload)?;
+
+    decode(version, &payload)
+}
+
+impl Map {
+    /// Write the map in the current save format.
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), MapError> {
+        let grid = &self.grid;
+        let saved = SavedMap {
+            seed: self.seed,
+            options: self.options.clone(),
+            rerolls: self.rerolls.clone(),
+            template: self.template,
+            grid: SavedGrid {
+                point_spacing: grid.point_spacing,
+                boundary: to_pairs(&grid.boundary),
+                points: to_pairs(&grid.points),
+                heights: grid.heights.clone(),
+                feature_map: grid.feature_map.clone(),
+                features: grid.features.clone(),
+                coasts: grid.coasts.clone(),
+            },
+        };
+
+        Ok(write_saved(writer, &saved)?)
+    }
+
+    /// Read a map written by `Map::save`, migrating it from older format
+    /// versions if needed.
+    pub fn load<R: Read>(reader: R) -> Result<Map, MapError> {
+        let saved = read_saved(reader)?;
+        let options = saved.options;
+        let saved_grid = saved.grid;
+
+        let mut grid = Grid::from_points(
+            options.size,
+            options.cells,
+            options.point_distribution,
+            options.topology,
+            saved_grid.point_spacing,
+            from_pairs(saved_grid.boundary),
+            from_pairs(saved_grid.points),
+        )?;
+        let cells = grid.voronoi.cell_count();
+        if saved_grid.heights.len() != cells || saved_grid.heights.iter().any(|h| !h.is_finite()) {
+            return Err(SaveError::Corrupt("heights").into());
+        }
+        let features = saved_grid.features.len();
+        if saved_grid.feature_map.len() != cells
+            || saved_grid.feature_map.iter().flatten().any(|&f| f >= features)
+        {
+            return Err(SaveError::Corrupt("feature map entries").into());
+        }
+        if saved_grid.coasts.len() != cells {
+            return Err(SaveError::Corrupt("coasts").into());
+        }
+        grid.heights = saved_grid.heights;
+        grid.feature_map = saved_grid.feature_map;
+        grid.features = saved_grid.features;
+        grid.coasts = saved_grid.coasts;
+        let pack = PackGrid::new(&grid)?;
+        let template = saved.template.or(options.template);
+
+        Ok(Map {
+            seed: saved.seed,
+            options,
+            rerolls: saved.rerolls,
+            template,
+            grid,
+            pack,
+            cancel: CancelToken::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_cbor::Value;
+
+    use super::*;
+    use crate::heightmap;
+
+    fn generate() -> Map {
+        let options = MapOptions {
+            size: crate::Size::new(200, 100),
+            cells: crate::MIN_CELLS,
+            ..MapOptions::default()
+        };
+        Map::generate_with_seed(options, 7).unwrap()
+    }
+
+    // A version 1 save of `map`'s grid, as written before cell counts,
+    // picked templates and fractional heights: the options have a density
+    // and a plain template, the map has no template of its own and the
+    // heights are whole. Features and coasts are saved as they were then.
+    fn version_1(map: &Map, density: u32) -> Value {
+        let text = |s: &str| Value::Text(s.to_owned());
+        let int = |i: i64| Value::Integer(i.into());
+        let float = |f: f32| Value::Float(f.into());
+        let object = |fields: Vec<(&str, Value)>| {
+            Value::Map(fields.into_iter().map(|(k, v)| (text(k), v)).collect())
+        };
+        let pairs = |points: &[Point]| {
+            Value::Array(points.iter().map(|p| Value::Array(vec![float(p.x), float(p.y)])).collect())
+        };
+
+        let size = map.options.size;
+        let options = object(vec![
+            ("density", int(density.into())),
+            ("template", text("Isthmus")),
+            ("winds", Value::Array([225, 45, 225, 315, 135, 315].iter().map(|&w| int(w)).collect())),
+            ("temperature_equator", int(27)),
+            ("temperature_pole", int(-30)),
+            ("prec", int(100)),
+            ("map_size", int(100)),
+            ("latitude", int(50)),
+            ("height_exponent", float(1.8)),
+            ("cultures", int(14)),
+            ("regions", int(13)),
+            ("provinces", int(30)),
+            ("power", float(5.0)),
+            ("neutral", float(1.0)),
+            ("manors", int(1000)),
+            ("religions", int(15)),
+            ("population_rate", int(1000)),
+            ("urbanization", float(1.0)),
+            ("size", object(vec![("width", int(size.width.into())), ("height", int(size.height.into()))])),
+        ]);
+
+        let grid = &map.grid;
+        let heights = grid.heights.iter().map(|&h| int(heightmap::whole_height(h).into())).collect();
+        object(vec![
+            ("seed", Value::Integer(map.seed.into())),
+            ("options", options),
+            ("rerolls", Value::Map(BTreeMap::new())),
+            ("grid", object(vec![
+                ("point_spacing", float(grid.point_spacing)),
+                ("boundary", pairs(&grid.boundary)),
+                ("points", pairs(&grid.points)),
+                ("heights", Value::Array(heights)),
+                ("feature_map", serde_cbor::value::to_value(&grid.feature_map).unwrap()),
+                ("features", serde_cbor::value::to_value(&grid.features).unwrap()),
+                ("coasts", serde_cbor::value::to_value(&grid.coasts).unwrap()),
+            ])),
+        ])
+    }
+
+    fn encode<T: Serialize>(version: u16, saved: &T) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&version.to_le_bytes());
+        serde_cbor::to_writer(&mut data, saved).unwrap();
+        data
+    }
+
+    #[test]
+    fn version_1_loads_with_whole_heights() {
+        let map = generate();
+        let loaded = Map::load(encode(1, &version_1(&map, 1)).as_slice()).unwrap();
+
+        assert_eq!(loaded.seed, map.seed);
+        assert_eq!(loaded.options.size, map.options.size);
+        assert_eq!(loaded.options.template, Some(Template::Isthmus));
+        assert_eq!(loaded.template, Some(Template::Isthmus));
+        let heights: Vec<_> = map.grid.heights
+            .iter()
+            .map(|&h| f32::from(heightmap::whole_height(h)))
+            .collect();
+        assert_eq!(loaded.grid.heights, heights);
+        assert_eq!(loaded.grid.feature_map, map.grid.feature_map);
+        assert_eq!(loaded.grid.coasts, map.grid.coasts);
+
+        // Saving again writes the current version, which loads as it is.
+        let mut data = Vec::new();
+        loaded.save(&mut data).unwrap();
+        assert_eq!(&data[MAGIC.len()..MAGIC.len() + 2], &FORMAT_VERSION.to_le_bytes());
+        let reloaded = Map::load(data.as_slice()).unwrap();
+        assert_eq!(reloaded.options, loaded.options);
+        assert_eq!(reloaded.grid.heights, heights);
+    }
+
+    #[test]
+    fn version_1_density_becomes_cells() {
+        let map = generate();
+        let loaded = Map::load(encode(1, &version_1(&map, 2)).as_slice()).unwrap();
+        assert_eq!(loaded.options.cells, 20_000);
+    }
+
+    #[test]
+    fn feature_map_past_features_is_corrupt() {
+        let mut map = generate();
+        map.grid.feature_map[0] = Some(map.grid.features.len());
+        let mut data = Vec::new();
+        map.save(&mut data).unwrap();
+
+        match Map::load(data.as_slice()) {
+            Err(MapError::Save(SaveError::Corrupt("feature map entries"))) => {}
+            other => panic!("expected a corrupt feature map, got {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn non_finite_heights_are_corrupt() {
+        let mut map = generate();
+        map.grid.heights[0] = f32::NAN;
+        let mut data = Vec::new();
+        map.save(&mut data).unwrap();
+
+        match Map::load(data.as_slice()) {
+            Err(MapError::Save(SaveError::Corrupt("heights"))) => {}
+            other => panic!("expected corrupt heights, got {:?}", other.err()),
+        }
+    }
+}
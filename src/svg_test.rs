@@ -1,26 +1,36 @@
+use std::path::Path as FsPath;
+
 use svg::Document;
 use svg::node::element::Path;
 
+use crate::MapError;
+
+pub const CELLS_FILE: &str = "cells.svg";
+pub const COASTLINE_FILE: &str = "coastline.svg";
+pub const HEIGHTMAP_FILE: &str = "heightmap.svg";
+
 pub fn remove_loading() {}
 
 pub fn undraw_all() {}
 
 pub fn unfog() {}
 
-pub fn __draw_cells(path: String) {
+pub fn _draw_cells(dir: &FsPath, path: String) -> Result<(), MapError> {
     let path = Path::new()
         .set("fill", "none")
         .set("stroke", "black")
         .set("stroke-width", 0.1)
         .set("d", path);
     let doc = Document::new().set("background-color", "white").add(path);
-    svg::save("cells.svg", &doc).expect("SVG failed to save");
+    svg::save(dir.join(CELLS_FILE), &doc)?;
+    Ok(())
 }
 
 pub fn clear_cells() {}
 
 // All inputs are arrays of strings
 pub fn _draw_coastline(
+    dir: &FsPath,
     land_mask_paths: &[String],
     water_mask_paths: &[String],
     coastline_paths: &[String],
@@ -28,7 +38,7 @@ pub fn _draw_coastline(
     lake_groups: &[String],
     lake_paths: &[String],
     lake_ids: &[String],
-) {
+) -> Result<(), MapError> {
     let mut doc = Document::new().set("background-color", "white");
     for path in land_mask_paths {
         let path = Path::new()
@@ -63,14 +73,16 @@ pub fn _draw_coastline(
             .set("d", lake_paths[i].as_str());
         doc = doc.add(path);
     }
-    svg::save("coastline.svg", &doc).expect("SVG failed to save");
+    svg::save(dir.join(COASTLINE_FILE), &doc)?;
+    Ok(())
 }
 
 pub fn _draw_heightmap(
+    dir: &FsPath,
     height_paths: &[String],
     height_colors: &[String],
-    height_values: &[u8],
-) {
+    height_values: &[f32],
+) -> Result<(), MapError> {
     let mut doc = Document::new().set("background-color", "white");
     for i in 0..height_paths.len() {
         let path = Path::new()
@@ -79,7 +91,8 @@ pub fn _draw_heightmap(
             .set("data-height", height_values[i]);
         doc = doc.add(path);
     }
-    svg::save("heightmap.svg", &doc).expect("SVG failed to save");
+    svg::save(dir.join(HEIGHTMAP_FILE), &doc)?;
+    Ok(())
 }
 
 pub fn clear_heightmap() {}
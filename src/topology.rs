@@ -0,0 +1,676 @@
+//! The shape of the surface a grid covers.
+//!
+//! A flat grid is a rectangle fenced in by boundary points. A cylindrical grid
+//! is only fenced in at the top and bottom, its left and right edges are
+//! triangulated as if the map repeated on both sides. A spherical grid covers
+//! a whole planet and is shown in the equirectangular projection, so its
+//! points and vertices have map coordinates like any other, but it has no
+//! boundary and its cells are measured on the sphere.
+
+use std::collections::{HashMap, HashSet};
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+
+use rand::Rng;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use triangulation::{Delaunay, EdgeIndex, Point, PointIndex};
+
+use crate::{MapError, Size};
+use crate::geometry::{self, Vec3};
+use crate::voronoi::{self, CellIndex, Triangulation, Voronoi};
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize)]
+pub enum Topology {
+    /// A rectangle with edges all around.
+    Flat,
+    /// A band without end from west to east. The left and right edges of the
+    /// map meet, the top and bottom are edges.
+    Cylinder,
+    /// A whole planet. The left and right edges of the map meet and the top
+    /// and bottom edges are the poles. The points always follow a jittered
+    /// Fibonacci spiral, the point distribution is ignored.
+    Sphere,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology::Flat
+    }
+}
+
+impl fmt::Display for Topology {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Topology::Flat => write!(f, "Flat"),
+            Topology::Cylinder => write!(f, "Cylinder"),
+            Topology::Sphere => write!(f, "Sphere"),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ParseTopologyError(String);
+
+impl fmt::Display for ParseTopologyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown topology \"{}\", expected flat, cylinder or sphere", self.0)
+    }
+}
+
+impl std::error::Error for ParseTopologyError {}
+
+impl FromStr for Topology {
+    type Err = ParseTopologyError;
+
+    /// Parse a topology case-insensitively.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "flat" => Ok(Topology::Flat),
+            "cylinder" | "wrap" => Ok(Topology::Cylinder),
+            "sphere" | "globe" => Ok(Topology::Sphere),
+            _ => Err(ParseTopologyError(s.to_owned())),
+        }
+    }
+}
+
+impl Topology {
+    /// Whether the left and right edges of the map meet.
+    pub fn wraps_horizontally(self) -> bool {
+        self != Topology::Flat
+    }
+
+    /// Triangulate `points` and build their voronoi graph. The boundary
+    /// points fence in flat and cylindrical grids.
+    pub(crate) fn voronoi(
+        self,
+        size: Size,
+        points: &[Point],
+        boundary: &[Point],
+    ) -> Result<Voronoi, MapError> {
+        match self {
+            Topology::Flat => {
+                let mut allpoints = Vec::with_capacity(points.len() + boundary.len());
+                allpoints.extend_from_slice(points);
+                allpoints.extend_from_slice(boundary);
+                let mut delaunay = Delaunay::new(allpoints.as_slice()).ok_or(MapError::Triangulation)?;
+                delaunay.dcel.init_revmap();
+                Ok(Voronoi::from_delaunay(&delaunay, &allpoints, points.len(), size))
+            }
+            Topology::Cylinder => cylinder_voronoi(size, points, boundary),
+            Topology::Sphere => {
+                let positions = points.iter().map(|&p| to_sphere(p, size)).collect();
+                let mesh = SphereMesh::new(positions).ok_or(MapError::Triangulation)?;
+                Ok(mesh.voronoi(size))
+            }
+        }
+    }
+
+    /// Squared distance between two positions on the map, across the seam
+    /// if that is shorter. On a sphere it is the distance along the surface
+    /// of a sphere with the same area as the map, as the map is stretched
+    /// more the closer to the poles.
+    pub fn distance_squared(self, a: Point, b: Point, size: Size) -> f32 {
+        if self == Topology::Sphere {
+            let radius = (size.width as f64 * size.height as f64 / (4.0 * PI)).sqrt();
+            let arc = geometry::arc_length(to_sphere(a, size), to_sphere(b, size)) * radius;
+            return (arc * arc) as f32;
+        }
+        let mut dx = a.x - b.x;
+        if self.wraps_horizontally() {
+            let width = size.width as f32;
+            dx = dx.abs();
+            dx = dx.min(width - dx);
+        }
+        dx.powi(2) + (a.y - b.y).powi(2)
+    }
+
+    /// The point halfway between `a` and `b`, the short way around on grids
+    /// that wrap.
+    pub(crate) fn midpoint(self, a: Point, b: Point, size: Size) -> Point {
+        match self {
+            Topology::Flat => Point::new((a.x + b.x) / 2.0, (a.y + b.y) / 2.0),
+            Topology::Cylinder => {
+                let width = size.width as f32;
+                let b_x = a.x + wrapped_dx(b.x - a.x, width);
+                Point::new(((a.x + b_x) / 2.0).rem_euclid(width), (a.y + b.y) / 2.0)
+            }
+            Topology::Sphere => {
+                let a = to_sphere(a, size);
+                let b = to_sphere(b, size);
+                to_map(geometry::normalize([a[0] + b[0], a[1] + b[1], a[2] + b[2]]), size)
+            }
+        }
+    }
+
+    /// The cell nearest to `(x, y)`, walking the graph from `start`.
+    ///
+    /// The locator measures distances on the map, without the seam and, on a
+    /// sphere, only close to the truth. Its answer is refined by moving to
+    /// whichever neighbor is nearer until none is.
+    pub(crate) fn refine_nearest(
+        self,
+        voronoi: &Voronoi,
+        points: &[Point],
+        size: Size,
+        start: CellIndex,
+        x: f32,
+        y: f32,
+    ) -> CellIndex {
+        if self == Topology::Flat || points.is_empty() {
+            return start;
+        }
+        let position = Point::new(x, y);
+        let target = to_sphere(position, size);
+        let closeness = |c: CellIndex| {
+            let p = points[c.as_usize()];
+            match self {
+                Topology::Sphere => geometry::dot(to_sphere(p, size), target),
+                _ => -self.distance_squared(p, position, size) as f64,
+            }
+        };
+        let mut current = start;
+        let mut best = closeness(current);
+        loop {
+            let next = voronoi
+                .cell(current)
+                .adjacent_cells
+                .iter()
+                .map(|&c| (c, closeness(c)))
+                .fold(None, |nearest: Option<(CellIndex, f64)>, (c, d)| match nearest {
+                    Some((_, nd)) if nd >= d => nearest,
+                    _ => Some((c, d)),
+                });
+            match next {
+                Some((c, d)) if d > best => {
+                    current = c;
+                    best = d;
+                }
+                _ => return current,
+            }
+        }
+    }
+}
+
+// Largest jitter of the spherical points, relative to their spacing.
+const SPHERE_JITTER: f64 = 0.3;
+
+/// `count` points spread evenly over the unit sphere along a Fibonacci
+/// spiral, each moved a little in a random direction.
+pub(crate) fn fibonacci_sphere(count: usize, rng: &mut StdRng) -> Vec<Vec3> {
+    let golden_angle = PI * (3.0 - 5f64.sqrt());
+    let spacing = (4.0 * PI / count as f64).sqrt();
+    (0..count)
+        .map(|i| {
+            let z = 1.0 - (2 * i + 1) as f64 / count as f64;
+            let r = (1.0 - z * z).sqrt();
+            let longitude = golden_angle * i as f64;
+            let p = [r * longitude.cos(), r * longitude.sin(), z];
+
+            // Keep only the part of the offset along the surface.
+            let offset = [rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0)];
+            let along = geometry::dot(offset, p);
+            let jitter = SPHERE_JITTER * spacing;
+            geometry::normalize([
+                p[0] + (offset[0] - along * p[0]) * jitter,
+                p[1] + (offset[1] - along * p[1]) * jitter,
+                p[2] + (offset[2] - along * p[2]) * jitter,
+            ])
+        })
+        .collect()
+}
+
+/// The point on the unit sphere shown at `p` in the equirectangular
+/// projection.
+pub(crate) fn to_sphere(p: Point, size: Size) -> Vec3 {
+    let longitude = p.x as f64 / size.width as f64 * 2.0 * PI - PI;
+    let latitude = PI / 2.0 - p.y as f64 / size.height as f64 * PI;
+    [
+        latitude.cos() * longitude.cos(),
+        latitude.cos() * longitude.sin(),
+        latitude.sin(),
+    ]
+}
+
+/// Where a point on the unit sphere is shown in the equirectangular
+/// projection.
+pub(crate) fn to_map(v: Vec3, size: Size) -> Point {
+    let longitude = v[1].atan2(v[0]);
+    let latitude = v[2].max(-1.0).min(1.0).asin();
+    Point::new(
+        ((longitude + PI) / (2.0 * PI) * size.width as f64) as f32,
+        ((PI / 2.0 - latitude) / PI * size.height as f64) as f32,
+    )
+}
+
+// `dx` moved by whole map widths to the shortest step.
+pub(crate) fn wrapped_dx(dx: f32, width: f32) -> f32 {
+    dx - (dx / width).round() * width
+}
+
+/// Move the points of a path by whole map widths where it crosses the seam, so
+/// that it is connected. Returns how many times the path closed back to its
+/// first point winds around the map.
+pub(crate) fn unwrap_path(path: &mut [Point], size: Size) -> i32 {
+    if path.is_empty() {
+        return 0;
+    }
+    let width = size.width as f32;
+    for i in 1..path.len() {
+        let previous = path[i - 1].x;
+        path[i].x = previous + wrapped_dx(path[i].x - previous, width);
+    }
+    let first = path[0];
+    let last = path[path.len() - 1];
+    ((last.x + wrapped_dx(first.x - last.x, width) - first.x) / width).round() as i32
+}
+
+/// Make a polygon that wraps around the map connected, and shift it to the
+/// side its center is on.
+///
+/// A polygon around a pole cannot be connected that way. It is carried on to
+/// its first point a map width over and closed along the top or bottom edge
+/// of the map instead, so it spans the whole width.
+pub(crate) fn unwrap_polygon(polygon: &mut Vec<Point>, size: Size) {
+    if polygon.is_empty() {
+        return;
+    }
+    let width = size.width as f32;
+    let winding = unwrap_path(polygon, size);
+    if winding != 0 {
+        let first = polygon[0];
+        let end = first.x + winding as f32 * width;
+        let mean_y = polygon.iter().map(|p| p.y).sum::<f32>() / polygon.len() as f32;
+        let edge = if mean_y < size.height as f32 / 2.0 { 0.0 } else { size.height as f32 };
+        polygon.push(Point::new(end, first.y));
+        polygon.push(Point::new(end, edge));
+        polygon.push(Point::new(first.x, edge));
+    }
+
+    let mean_x = polygon.iter().map(|p| p.x).sum::<f32>() / polygon.len() as f32;
+    let shift = (mean_x / width).floor() * width;
+    for p in polygon.iter_mut() {
+        p.x -= shift;
+    }
+}
+
+// Width of the strips copied to the other side of the map before
+// triangulating a cylinder, in point spacings.
+const SEAM_MARGIN: f32 = 6.0;
+// How far off the map a circumcenter may be and still count as on it.
+// Rounding can put a circumcenter right on the seam just off the map on both
+// sides.
+const SEAM_SLACK: f32 = 0.01;
+
+/// The voronoi graph of a cylinder.
+///
+/// The points near either side are copied over to the other side, and of the
+/// triangles of them all only those with their circumcenter on the map are
+/// kept, with the copies replaced by the originals. Each triangle across the
+/// seam is kept exactly once that way, even with its circumcenter on the seam.
+fn cylinder_voronoi(size: Size, points: &[Point], boundary: &[Point]) -> Result<Voronoi, MapError> {
+    let width = size.width as f32;
+    let spacing = (size.width as f32 * size.height as f32 / points.len().max(1) as f32).sqrt();
+    let margin = (SEAM_MARGIN * spacing).min(width / 2.0);
+
+    let mut allpoints = Vec::with_capacity(points.len() + boundary.len());
+    allpoints.extend_from_slice(points);
+    allpoints.extend_from_slice(boundary);
+    let mut originals: Vec<_> = (0..allpoints.len()).collect();
+    for i in 0..allpoints.len() {
+        let p = allpoints[i];
+        if p.x < margin {
+            allpoints.push(Point::new(p.x + width, p.y));
+            originals.push(i);
+        }
+        if p.x >= width - margin {
+            allpoints.push(Point::new(p.x - width, p.y));
+            originals.push(i);
+        }
+    }
+    let delaunay = Delaunay::new(allpoints.as_slice()).ok_or(MapError::Triangulation)?;
+
+    let mut edge_points = Vec::new();
+    let mut circumcenters = Vec::new();
+    let mut kept = HashSet::new();
+    for t in (0..delaunay.edge_points().len()).step_by(3) {
+        let center = delaunay.dcel.triangle(t.into(), &allpoints).circumcenter();
+        if center.x < -SEAM_SLACK || center.x >= width + SEAM_SLACK {
+            continue;
+        }
+        let corners: Vec<_> = delaunay.edge_points()[t..t + 3]
+            .iter()
+            .map(|p| originals[p.as_usize()])
+            .collect();
+        let mut triangle = [corners[0], corners[1], corners[2]];
+        triangle.sort_unstable();
+        if !kept.insert(triangle) {
+            continue;
+        }
+        edge_points.extend(corners.into_iter().map(PointIndex::from));
+        circumcenters.push(Point::new(center.x.rem_euclid(width), center.y));
+    }
+
+    let mut edges = HashMap::with_capacity(edge_points.len());
+    for e in 0..edge_points.len() {
+        let next = voronoi::next_edge(e.into()).as_usize();
+        edges.insert((edge_points[e].as_usize(), edge_points[next].as_usize()), EdgeIndex::from(e));
+    }
+    let twins = (0..edge_points.len())
+        .map(|e| {
+            let next = voronoi::next_edge(e.into()).as_usize();
+            edges.get(&(edge_points[next].as_usize(), edge_points[e].as_usize())).copied()
+        })
+        .collect();
+    let mesh = Mesh::new(edge_points, twins, points.len() + boundary.len());
+
+    let mut voronoi = Voronoi::from_triangulation(
+        &mesh,
+        &circumcenters,
+        points.len(),
+        size,
+        Topology::Cylinder,
+    );
+    // Measure each cell with its vertices on the same side as its point.
+    let near = |p: Point, x: f32| Point::new(x + wrapped_dx(p.x - x, width), p.y);
+    voronoi.compute_geometry(
+        |cell, vertices| {
+            let point = points[cell.as_usize()];
+            let polygon: Vec<_> = vertices
+                .iter()
+                .map(|v| near(circumcenters[v.as_usize()], point.x))
+                .collect();
+            let clipped = geometry::clip_polygon_vertically(&polygon, size);
+            let (area, centroid) = geometry::area_centroid(&clipped);
+            let centroid = centroid.map_or(point, |c| Point::new(c.x.rem_euclid(width), c.y));
+            (area, centroid, geometry::perimeter(&clipped))
+        },
+        |a, b| {
+            let a = circumcenters[a.as_usize()];
+            let b = near(circumcenters[b.as_usize()], a.x);
+            geometry::clipped_length_vertically(a, b, size)
+        },
+    );
+    Ok(voronoi)
+}
+
+/// A triangulation put together here rather than by `Delaunay`.
+pub(crate) struct Mesh {
+    edge_points: Vec<PointIndex>,
+    twins: Vec<Option<EdgeIndex>>,
+    // An outgoing half-edge of every point, `None` for points left out of the
+    // triangulation.
+    first_edges: Vec<Option<EdgeIndex>>,
+}
+
+impl Mesh {
+    fn new(edge_points: Vec<PointIndex>, twins: Vec<Option<EdgeIndex>>, point_count: usize) -> Self {
+        let mut first_edges = vec![None; point_count];
+        for (e, &p) in edge_points.iter().enumerate() {
+            // Points on the hull have to start at the hull to be walked all
+            // the way around.
+            let on_hull = twins[voronoi::prev_edge(e.into()).as_usize()].is_none();
+            let first = &mut first_edges[p.as_usize()];
+            if first.is_none() || on_hull {
+                *first = Some(EdgeIndex::from(e));
+            }
+        }
+        Mesh {
+            edge_points,
+            twins,
+            first_edges,
+        }
+    }
+}
+
+impl Triangulation for Mesh {
+    fn edge_points(&self) -> &[PointIndex] {
+        &self.edge_points
+    }
+
+    fn twin(&self, e: EdgeIndex) -> Option<EdgeIndex> {
+        self.twins[e.as_usize()]
+    }
+
+    fn outgoing_edges(&self, p: PointIndex, edges: &mut Vec<EdgeIndex>) {
+        let start = match self.first_edges[p.as_usize()] {
+            Some(e) => e,
+            None => return,
+        };
+        let mut e = start;
+        loop {
+            edges.push(e);
+            match self.twins[voronoi::prev_edge(e).as_usize()] {
+                Some(next) if next != start => e = next,
+                _ => break,
+            }
+        }
+    }
+}
+
+/// A closed triangulation of points on the unit sphere.
+pub(crate) struct SphereMesh {
+    positions: Vec<Vec3>,
+    mesh: Mesh,
+}
+
+impl SphereMesh {
+    /// Triangulate points on the unit sphere, `None` if that fails.
+    ///
+    /// The points are projected stereographically from the last one. The
+    /// projection keeps circles circles, so the planar Delaunay triangulation
+    /// of the others is the spherical one, apart from the triangles around
+    /// the last point, which are added along the hull.
+    pub fn new(positions: Vec<Vec3>) -> Option<Self> {
+        let count = positions.len();
+        if count < 4 {
+            return None;
+        }
+        let pole = positions[count - 1];
+        let axis = if pole[0].abs() < 0.9 { [1.0, 0.0, 0.0] } else { [0.0, 1.0, 0.0] };
+        let u = geometry::normalize(geometry::cross(pole, axis));
+        let v = geometry::cross(pole, u);
+        let projected: Vec<_> = positions[..count - 1]
+            .iter()
+            .map(|&p| {
+                let d = 1.0 - geometry::dot(p, pole);
+                Point::new((geometry::dot(p, u) / d) as f32, (geometry::dot(p, v) / d) as f32)
+            })
+            .collect();
+        let delaunay = Delaunay::new(projected.as_slice())?;
+
+        let mut edge_points = delaunay.edge_points().to_vec();
+        let mut twins: Vec<_> = (0..edge_points.len()).map(|e| delaunay.twin(e.into())).collect();
+
+        // Close the hull with a fan of triangles around the pole, each
+        // turning a hull edge `a -> b` into the triangle `b, a, pole`.
+        let pole_index = PointIndex::from(count - 1);
+        let hull: Vec<_> = (0..edge_points.len()).filter(|&e| twins[e].is_none()).collect();
+        let mut fan_from = vec![None; count];
+        let mut fan_to = vec![None; count];
+        for &e in &hull {
+            let a = edge_points[e];
+            let b = edge_points[voronoi::next_edge(e.into()).as_usize()];
+            let t = edge_points.len();
+            edge_points.extend_from_slice(&[b, a, pole_index]);
+            twins.extend_from_slice(&[Some(e.into()), None, None]);
+            twins[e] = Some(t.into());
+            fan_from[a.as_usize()] = Some(t);
+            fan_to[b.as_usize()] = Some(t);
+        }
+        // Edge `a -> pole` of one fan triangle is the twin of `pole -> a` of
+        // the one before it.
+        for &e in &hull {
+            let a = edge_points[e].as_usize();
+            let t = fan_from[a]?;
+            let before = fan_to[a]?;
+            twins[t + 1] = Some((before + 2).into());
+            twins[before + 2] = Some((t + 1).into());
+        }
+        if twins.iter().any(Option::is_none) {
+            return None;
+        }
+
+        Some(SphereMesh {
+            mesh: Mesh::new(edge_points, twins, count),
+            positions,
+        })
+    }
+
+    /// Build the voronoi graph, with the vertices in map coordinates and the
+    /// cells measured on a sphere with the same area as the map.
+    pub fn voronoi(&self, size: Size) -> Voronoi {
+        let edge_points = &self.mesh.edge_points;
+        let circumcenters: Vec<_> = (0..edge_points.len() / 3)
+            .map(|t| {
+                let [a, b, c] = [
+                    self.positions[edge_points[3 * t].as_usize()],
+                    self.positions[edge_points[3 * t + 1].as_usize()],
+                    self.positions[edge_points[3 * t + 2].as_usize()],
+                ];
+                let normal = geometry::cross(
+                    [b[0] - a[0], b[1] - a[1], b[2] - a[2]],
+                    [c[0] - a[0], c[1] - a[1], c[2] - a[2]],
+                );
+                let center = geometry::normalize(normal);
+                if geometry::dot(center, a) < 0.0 {
+                    [-center[0], -center[1], -center[2]]
+                } else {
+                    center
+                }
+            })
+            .collect();
+        let coords: Vec<_> = circumcenters.iter().map(|&c| to_map(c, size)).collect();
+        let mut voronoi = Voronoi::from_triangulation(
+            &self.mesh,
+            &coords,
+            self.positions.len(),
+            size,
+            Topology::Sphere,
+        );
+
+        let radius = (size.width as f64 * size.height as f64 / (4.0 * PI)).sqrt();
+        voronoi.compute_geometry(
+            |cell, vertices| {
+                let center = self.positions[cell.as_usize()];
+                let mut area = 0.0;
+                let mut perimeter = 0.0;
+                let mut centroid = [0.0; 3];
+                for (k, v) in vertices.iter().enumerate() {
+                    let a = circumcenters[v.as_usize()];
+                    let b = circumcenters[vertices[(k + 1) % vertices.len()].as_usize()];
+                    let triangle = geometry::spherical_triangle_area(center, a, b);
+                    let middle = geometry::normalize([
+                        center[0] + a[0] + b[0],
+                        center[1] + a[1] + b[1],
+                        center[2] + a[2] + b[2],
+                    ]);
+                    for i in 0..3 {
+                        centroid[i] += middle[i] * triangle;
+                    }
+                    area += triangle;
+                    perimeter += geometry::arc_length(a, b);
+                }
+                let centroid = if area > 0.0 { geometry::normalize(centroid) } else { center };
+                (
+                    (area * radius * radius) as f32,
+                    to_map(centroid, size),
+                    (perimeter * radius) as f32,
+                )
+            },
+            |a, b| (geometry::arc_length(circumcenters[a.as_usize()], circumcenters[b.as_usize()]) * radius) as f32,
+        );
+        voronoi
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::{CancelToken, Grid, PointDistribution};
+
+    fn grid(topology: Topology) -> Grid {
+        let mut rng = StdRng::seed_from_u64(3);
+        Grid::new(
+            Size::new(300, 200),
+            crate::MIN_CELLS,
+            PointDistribution::Jittered,
+            topology,
+            &mut rng,
+            &CancelToken::new(),
+        ).unwrap()
+    }
+
+    #[test]
+    fn steps_the_short_way_across_the_seam() {
+        assert_eq!(wrapped_dx(290.0, 300.0), -10.0);
+        assert_eq!(wrapped_dx(-290.0, 300.0), 10.0);
+        assert_eq!(wrapped_dx(20.0, 300.0), 20.0);
+
+        let size = Size::new(300, 200);
+        let a = Point::new(5.0, 50.0);
+        let b = Point::new(295.0, 50.0);
+        assert_eq!(Topology::Cylinder.distance_squared(a, b, size), 100.0);
+        assert_eq!(Topology::Flat.distance_squared(a, b, size), 290.0 * 290.0);
+    }
+
+    #[test]
+    fn unwraps_paths_across_the_seam() {
+        let size = Size::new(300, 200);
+        let mut path = [Point::new(290.0, 10.0), Point::new(5.0, 20.0), Point::new(295.0, 30.0)];
+        assert_eq!(unwrap_path(&mut path, size), 0);
+        assert_eq!(path.iter().map(|p| p.x).collect::<Vec<_>>(), [290.0, 305.0, 295.0]);
+
+        // A path once around the map.
+        let mut path = [Point::new(0.0, 10.0), Point::new(100.0, 10.0), Point::new(200.0, 10.0)];
+        assert_eq!(unwrap_path(&mut path, size), 1);
+    }
+
+    #[test]
+    fn finds_the_nearest_cell_across_the_seam() {
+        let grid = grid(Topology::Cylinder);
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..500 {
+            let (x, y) = (rng.gen_range(0.0, 300.0), rng.gen_range(0.0, 200.0));
+            let p = Point::new(x, y);
+            let distance = |c: CellIndex| Topology::Cylinder.distance_squared(grid.points[c.as_usize()], p, grid.size);
+            let best = grid.voronoi.cells().map(distance).fold(std::f32::INFINITY, f32::min);
+            assert_eq!(distance(grid.coords_to_cell_index(x, y)), best, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn projects_to_the_sphere_and_back() {
+        let size = Size::new(300, 200);
+        for &(x, y) in &[(0.5, 100.0), (150.0, 50.0), (299.0, 180.0)] {
+            let p = to_map(to_sphere(Point::new(x, y), size), size);
+            assert!((p.x - x).abs() < 1e-3 && (p.y - y).abs() < 1e-3, "({}, {}) became {:?}", x, y, p);
+        }
+    }
+
+    #[test]
+    fn sphere_is_closed() {
+        let grid = grid(Topology::Sphere);
+        let voronoi = &grid.voronoi;
+        let edges: usize = voronoi.cells().map(|c| voronoi.cell(c).adjacent_cells.len()).sum();
+        assert_eq!(edges % 2, 0);
+        assert_eq!(voronoi.vertex_count() + voronoi.cell_count(), edges / 2 + 2);
+        assert!(voronoi.cells().all(|c| !voronoi.cell(c).border_cell));
+    }
+
+    #[test]
+    fn finds_the_nearest_cell_on_a_sphere() {
+        let grid = grid(Topology::Sphere);
+        let mut rng = StdRng::seed_from_u64(4);
+        for _ in 0..500 {
+            let (x, y) = (rng.gen_range(0.0, 300.0), rng.gen_range(0.0, 200.0));
+            let target = to_sphere(Point::new(x, y), grid.size);
+            let closeness = |c: CellIndex| geometry::dot(to_sphere(grid.points[c.as_usize()], grid.size), target);
+            let best = grid.voronoi.cells().map(closeness).fold(-1.0, f64::max);
+            assert_eq!(closeness(grid.coords_to_cell_index(x, y)), best, "({}, {})", x, y);
+        }
+    }
+}
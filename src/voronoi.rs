@@ -1,112 +1,492 @@
-use std::collections::HashMap;
+//! Voronoi graph dual to the Delaunay triangulation of the grid points.
+//!
+//! Cells and vertices are addressed by dense indices: cell `i` belongs to
+//! point `i` and vertex `t` to triangle `t`. The per-cell lists are stored back
+//! to back in flat arrays, so the entries of cell `i` are those between
+//! `offsets[i]` and `offsets[i + 1]`.
+//!
+//! The geometry of each cell is computed from the exact circumcenters and
+//! clipped to the map, while `VoronoiVertex::coords` are rounded down for
+//! drawing.
 
 use triangulation::{Delaunay, EdgeIndex, Point, PointIndex};
 
+use crate::Size;
+use crate::geometry;
+use crate::topology::{self, Topology};
+
+macro_rules! index_type {
+    ( $( #[$attr:meta] )* $name:ident ) => {
+        $( #[$attr] )*
+        #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+        pub struct $name(u32);
+
+        impl $name {
+            pub fn as_usize(self) -> usize {
+                self.0 as usize
+            }
+        }
+
+        impl From<usize> for $name {
+            fn from(i: usize) -> Self {
+                $name(i as u32)
+            }
+        }
+
+        impl From<$name> for usize {
+            fn from(i: $name) -> Self {
+                i.as_usize()
+            }
+        }
+    }
+}
+
+index_type! {
+    /// Index of a cell, the same as the index of its center point. Indices
+    /// past the last cell refer to the points on the grid boundary.
+    CellIndex
+}
+
+index_type! {
+    /// Index of a voronoi vertex, the same as the index of its triangle.
+    VertexIndex
+}
+
+impl From<PointIndex> for CellIndex {
+    fn from(p: PointIndex) -> Self {
+        p.as_usize().into()
+    }
+}
+
+#[derive(Default)]
 pub struct Voronoi {
+    size: Size,
+    topology: Topology,
     center_points: usize,
-    pub cells: HashMap<PointIndex, VoronoiCell>,
-    pub vertices: HashMap<EdgeIndex, VoronoiVertex>,
+    vertex_offsets: Vec<u32>,
+    cell_vertices: Vec<VertexIndex>,
+    adjacent_offsets: Vec<u32>,
+    adjacent_cells: Vec<CellIndex>,
+    border_cells: Vec<bool>,
+    areas: Vec<f32>,
+    centroids: Vec<Point>,
+    perimeters: Vec<f32>,
+    // Parallel to `adjacent_cells`.
+    edge_lengths: Vec<f32>,
+    vertices: Vec<VoronoiVertex>,
 }
 
-pub struct VoronoiCell {
-    pub vertices: Vec<EdgeIndex>,
-    pub adjacent_cells: Vec<PointIndex>,
+/// The lists of a single cell, borrowed from the `Voronoi`.
+#[derive(Copy, Clone)]
+pub struct VoronoiCell<'a> {
+    pub vertices: &'a [VertexIndex],
+    pub adjacent_cells: &'a [CellIndex],
     pub border_cell: bool,
+    /// Area of the part of the cell on the map.
+    pub area: f32,
+    /// Centroid of the part of the cell on the map.
+    pub centroid: Point,
+    /// Perimeter of the part of the cell on the map, map edges included.
+    pub perimeter: f32,
+    /// Length of the edge shared with each of `adjacent_cells`, in the same
+    /// order.
+    pub edge_lengths: &'a [f32],
 }
 
 pub struct VoronoiVertex {
     pub coords: Point,
-    pub connected_vertices: [Option<EdgeIndex>; 3],
-    pub connected_cells: [PointIndex; 3],
+    pub connected_vertices: [Option<VertexIndex>; 3],
+    pub connected_cells: [CellIndex; 3],
+}
+
+/// A triangulation in half-edge form, like the one of `Delaunay`: the edges
+/// of triangle `t` are `3 * t`, `3 * t + 1` and `3 * t + 2`.
+pub(crate) trait Triangulation {
+    /// The point each half-edge starts at.
+    fn edge_points(&self) -> &[PointIndex];
+
+    /// The half-edge going the other way, `None` on the hull.
+    fn twin(&self, e: EdgeIndex) -> Option<EdgeIndex>;
+
+    /// Collect the half-edges starting at `p` into `edges`, in order around
+    /// the point.
+    fn outgoing_edges(&self, p: PointIndex, edges: &mut Vec<EdgeIndex>);
+}
+
+impl Triangulation for Delaunay {
+    fn edge_points(&self) -> &[PointIndex] {
+        &self.dcel.vertices
+    }
+
+    fn twin(&self, e: EdgeIndex) -> Option<EdgeIndex> {
+        self.dcel.twin(e)
+    }
+
+    fn outgoing_edges(&self, p: PointIndex, edges: &mut Vec<EdgeIndex>) {
+        edges.extend(self.dcel.outgoing_edges(p));
+    }
+}
+
+pub(crate) fn next_edge(e: EdgeIndex) -> EdgeIndex {
+    if e.as_usize() % 3 == 2 { (e.as_usize() - 2).into() } else { (e.as_usize() + 1).into() }
+}
+
+pub(crate) fn prev_edge(e: EdgeIndex) -> EdgeIndex {
+    if e.as_usize() % 3 == 0 { (e.as_usize() + 2).into() } else { (e.as_usize() - 1).into() }
+}
+
+fn vertex_index(e: EdgeIndex) -> VertexIndex {
+    VertexIndex::from(e.as_usize() / 3)
 }
 
 impl Voronoi {
-    pub fn from_delaunay(delaunay: &Delaunay, points: &[Point], num_center_points: usize) -> Self {
+    pub fn from_delaunay(
+        delaunay: &Delaunay,
+        points: &[Point],
+        num_center_points: usize,
+        size: Size,
+    ) -> Self {
         let dcel = &delaunay.dcel;
+        let circumcenters: Vec<_> = (0..dcel.vertices.len())
+            .step_by(3)
+            .map(|t| dcel.triangle(t.into(), points).circumcenter())
+            .collect();
+        let mut voronoi = Voronoi::from_triangulation(
+            delaunay,
+            &circumcenters,
+            num_center_points,
+            size,
+            Topology::Flat,
+        );
+
+        voronoi.compute_geometry(
+            |cell, vertices| {
+                let polygon: Vec<_> = vertices.iter().map(|v| circumcenters[v.as_usize()]).collect();
+                let clipped = geometry::clip_polygon(&polygon, size);
+                let (area, centroid) = geometry::area_centroid(&clipped);
+                let centroid = centroid.unwrap_or(points[cell.as_usize()]);
+                (area, centroid, geometry::perimeter(&clipped))
+            },
+            |a, b| geometry::clipped_length(circumcenters[a.as_usize()], circumcenters[b.as_usize()], size),
+        );
+        voronoi
+    }
+
+    /// Build the graph of the first `num_center_points` points of
+    /// `triangulation`, with its vertices at `circumcenters`, one per
+    /// triangle. The cell geometry is left to the caller.
+    pub(crate) fn from_triangulation<T: Triangulation>(
+        triangulation: &T,
+        circumcenters: &[Point],
+        num_center_points: usize,
+        size: Size,
+        topology: Topology,
+    ) -> Self {
+        let edge_points = triangulation.edge_points();
+
+        // Points missing from the triangulation, e.g. duplicates, get an
+        // empty cell.
+        let mut triangulated = vec![false; num_center_points];
+        for &point in edge_points {
+            if point.as_usize() < num_center_points {
+                triangulated[point.as_usize()] = true;
+            }
+        }
 
         let mut voronoi = Voronoi {
+            size,
+            topology,
             center_points: num_center_points,
-            cells: HashMap::with_capacity(num_center_points),
-            vertices: HashMap::with_capacity(points.len()),
+            vertex_offsets: Vec::with_capacity(num_center_points + 1),
+            cell_vertices: Vec::with_capacity(edge_points.len()),
+            adjacent_offsets: Vec::with_capacity(num_center_points + 1),
+            adjacent_cells: Vec::with_capacity(edge_points.len()),
+            border_cells: Vec::with_capacity(num_center_points),
+            areas: Vec::with_capacity(num_center_points),
+            centroids: Vec::with_capacity(num_center_points),
+            perimeters: Vec::with_capacity(num_center_points),
+            edge_lengths: Vec::with_capacity(edge_points.len()),
+            vertices: Vec::with_capacity(edge_points.len() / 3),
         };
+        voronoi.vertex_offsets.push(0);
+        voronoi.adjacent_offsets.push(0);
 
-        for edge in (0..dcel.vertices.len()).map(|e| EdgeIndex::from(e)) {
-            // Get the point that this edge points from.
-            let point = dcel.vertices[edge];
-            // If this point is not on the boundry and we have not created a
-            // voronoi cell for it, create one.
-            if point < num_center_points.into() && voronoi.cells.get(&point).is_none() {
-                // Outgoing edges from this point.
-                let edges: Vec<_> = dcel.outgoing_edges(point).collect();
-                // The triangles that use this point.
-                let vertices: Vec<_> = edges.iter().map(|&e| dcel.triangle_first_edge(e)).collect();
-                // End point of the outgoing edges. Points kept only if they
-                // are not on the boundry.
-                let adjacent_cells: Vec<_> = edges
-                    .iter()
-                    .map(|&e| dcel.vertices[dcel.next_edge(e)])
-                    .filter(|&c| c < num_center_points.into())
-                    .collect();
-                // If the number of incoming edges is greater than the number of
-                // adjacent cells, then this cell is on the border.
-                let border_cell = edges.len() > adjacent_cells.len();
-                voronoi.cells.insert(
-                    point,
-                    VoronoiCell {
-                        vertices,
-                        adjacent_cells,
-                        border_cell,
+        let mut outgoing = Vec::new();
+        for point in (0..num_center_points).map(PointIndex::from) {
+            let mut edges = 0;
+            let mut adjacent = 0;
+            if triangulated[point.as_usize()] {
+                outgoing.clear();
+                triangulation.outgoing_edges(point, &mut outgoing);
+                for &e in &outgoing {
+                    edges += 1;
+                    // The triangles that use this point.
+                    voronoi.cell_vertices.push(vertex_index(e));
+                    // End point of the outgoing edge, kept only if it is not
+                    // on the boundry.
+                    let c = edge_points[next_edge(e).as_usize()];
+                    if c < num_center_points.into() {
+                        adjacent += 1;
+                        voronoi.adjacent_cells.push(c.into());
                     }
-                );
+                }
             }
+            voronoi.vertex_offsets.push(voronoi.cell_vertices.len() as u32);
+            voronoi.adjacent_offsets.push(voronoi.adjacent_cells.len() as u32);
+            // If the number of incoming edges is greater than the number of
+            // adjacent cells, then this cell is on the border.
+            voronoi.border_cells.push(edges > adjacent);
+        }
+
+        for (t, point) in circumcenters.iter().enumerate() {
+            let edges = [EdgeIndex::from(3 * t), (3 * t + 1).into(), (3 * t + 2).into()];
+            let [a, b, c] = [
+                edge_points[3 * t],
+                edge_points[3 * t + 1],
+                edge_points[3 * t + 2],
+            ];
+            voronoi.vertices.push(VoronoiVertex {
+                coords: Point {
+                    x: point.x.floor(),
+                    y: point.y.floor(),
+                },
+                connected_vertices: [
+                    triangulation.twin(edges[0]).map(vertex_index),
+                    triangulation.twin(edges[1]).map(vertex_index),
+                    triangulation.twin(edges[2]).map(vertex_index),
+                ],
+                connected_cells: [a.into(), b.into(), c.into()],
+            });
+        }
+
+        voronoi
+    }
 
-            let triangle = dcel.triangle_first_edge(edge);
-            // Create a vertex for this triangle if one doesn't yet exist.
-            if voronoi.vertices.get(&triangle).is_none() {
-                let point = dcel.triangle(triangle, points).circumcenter();
-                let edges = dcel.triangle_edges(triangle);
-                let mut verts = edges
+    /// Fill in the area, centroid and perimeter of every cell from
+    /// `cell_shape`, and the length of each edge between two vertices from
+    /// `edge_length`.
+    pub(crate) fn compute_geometry<S, L>(&mut self, cell_shape: S, edge_length: L)
+    where
+        S: Fn(CellIndex, &[VertexIndex]) -> (f32, Point, f32),
+        L: Fn(VertexIndex, VertexIndex) -> f32,
+    {
+        self.areas.clear();
+        self.centroids.clear();
+        self.perimeters.clear();
+        self.edge_lengths.clear();
+        for cell in self.cells() {
+            let i = cell.as_usize();
+            let vertices = &self.cell_vertices[
+                self.vertex_offsets[i] as usize..self.vertex_offsets[i + 1] as usize
+            ];
+            let (area, centroid, perimeter) = cell_shape(cell, vertices);
+            self.areas.push(area);
+            self.centroids.push(centroid);
+            self.perimeters.push(perimeter);
+
+            // Each pair of consecutive vertices borders the one other cell
+            // both vertices touch.
+            let edges: Vec<_> = (0..vertices.len())
+                .map(|k| {
+                    let a = vertices[k];
+                    let b = vertices[(k + 1) % vertices.len()];
+                    let neighbor = self.vertices[a.as_usize()]
+                        .connected_cells
+                        .iter()
+                        .copied()
+                        .find(|&c| c != cell && self.vertices[b.as_usize()].connected_cells.contains(&c));
+                    (neighbor, edge_length(a, b))
+                })
+                .collect();
+            let adjacent = self.adjacent_offsets[i] as usize..self.adjacent_offsets[i + 1] as usize;
+            for a in adjacent {
+                let neighbor = self.adjacent_cells[a];
+                let length = edges
                     .iter()
-                    .map(|&e| delaunay.dcel.twin(e).map(|e| delaunay.dcel.triangle_first_edge(e)));
-                let vertex = VoronoiVertex {
-                    coords: Point {
-                        x: point.x.floor(),
-                        y: point.y.floor(),
-                    },
-                    // TODO: `flatten` function
-                    connected_vertices: [
-                        verts.next().and_then(|e| e),
-                        verts.next().and_then(|e| e),
-                        verts.next().and_then(|e| e),
-                    ],
-                    connected_cells: dcel.triangle_points(triangle),
-                };
-                voronoi.vertices.insert(triangle, vertex);
+                    .find(|(n, _)| *n == Some(neighbor))
+                    .map_or(0.0, |&(_, length)| length);
+                self.edge_lengths.push(length);
             }
         }
+    }
 
-        voronoi
+    pub fn size(&self) -> Size {
+        self.size
+    }
+
+    pub fn topology(&self) -> Topology {
+        self.topology
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.border_cells.len()
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertices.len()
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = CellIndex> {
+        (0..self.cell_count()).map(CellIndex::from)
+    }
+
+    pub fn cell(&self, cell: CellIndex) -> VoronoiCell<'_> {
+        let i = cell.as_usize();
+        let vertices = self.vertex_offsets[i] as usize..self.vertex_offsets[i + 1] as usize;
+        let adjacent = self.adjacent_offsets[i] as usize..self.adjacent_offsets[i + 1] as usize;
+        VoronoiCell {
+            vertices: &self.cell_vertices[vertices],
+            adjacent_cells: &self.adjacent_cells[adjacent.clone()],
+            border_cell: self.border_cells[i],
+            area: self.areas[i],
+            centroid: self.centroids[i],
+            perimeter: self.perimeters[i],
+            edge_lengths: &self.edge_lengths[adjacent],
+        }
+    }
+
+    pub fn vertex(&self, vertex: VertexIndex) -> &VoronoiVertex {
+        &self.vertices[vertex.as_usize()]
     }
 
     pub fn get_cell_vertices<'a>(
         &'a self,
-        cell_index: PointIndex
-    ) -> Option<impl Iterator<Item = &VoronoiVertex> + 'a> {
-        self.cells.get(&cell_index).map(|c|
-            c.vertices.iter().map(move |v| self.vertices.get(v).expect("No vertices for cell"))
-        )
+        cell_index: CellIndex
+    ) -> Option<impl Iterator<Item = &'a VoronoiVertex> + 'a> {
+        if cell_index.as_usize() >= self.cell_count() {
+            return None;
+        }
+        Some(self.cell(cell_index).vertices.iter().map(move |&v| self.vertex(v)))
     }
 
     pub fn get_cell_vertex_coords<'a>(
         &'a self,
     ) -> impl Iterator<Item = impl Iterator<Item = Point> + 'a> + 'a {
-        (0..self.center_points)
-            .map(PointIndex::from)
-            .filter_map(move |p| self.get_cell_vertices(p))
+        self.cells()
+            .filter_map(move |c| self.get_cell_vertices(c))
             .map(|i| i.map(|v| v.coords))
     }
 
-    pub fn is_border_point(&self, p: PointIndex) -> bool {
+    /// Vertex coordinates of a cell clipped to the map, `None` if there is no
+    /// such cell. Cells at the edge of the map reach past it to the
+    /// circumcenters of the boundary triangles, their clipped polygons end at
+    /// the map edge instead. A cell across the seam of a map that wraps around
+    /// is split in two, with a polygon on either side.
+    pub fn get_clipped_cell_vertices(&self, cell_index: CellIndex) -> Option<Vec<Vec<Point>>> {
+        let mut polygon: Vec<_> = self.get_cell_vertices(cell_index)?.map(|v| v.coords).collect();
+        if !self.topology.wraps_horizontally() {
+            return Some(vec![geometry::clip_polygon(&polygon, self.size)]);
+        }
+
+        topology::unwrap_polygon(&mut polygon, self.size);
+        let width = self.size.width as f32;
+        let parts = [-width, 0.0, width]
+            .iter()
+            .map(|&shift| {
+                let shifted: Vec<_> = polygon.iter().map(|p| Point::new(p.x + shift, p.y)).collect();
+                geometry::clip_polygon(&shifted, self.size)
+            })
+            .filter(|part| part.len() >= 3)
+            .collect();
+        Some(parts)
+    }
+
+    /// Like `get_cell_vertex_coords`, but clipped to the map, so the cells
+    /// tile the map rectangle exactly.
+    pub fn get_clipped_cell_vertex_coords<'a>(&'a self) -> impl Iterator<Item = Vec<Point>> + 'a {
+        self.cells()
+            .filter_map(move |c| self.get_clipped_cell_vertices(c))
+            .flatten()
+    }
+
+    pub fn is_border_point(&self, p: CellIndex) -> bool {
         p.as_usize() >= self.center_points
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+    use crate::{CancelToken, Grid, PointDistribution};
+
+    #[test]
+    fn clipped_cells_tile_the_map() {
+        for &topology in &[Topology::Flat, Topology::Cylinder, Topology::Sphere] {
+            let mut rng = StdRng::seed_from_u64(5);
+            let size = Size::new(300, 200);
+            let grid = Grid::new(
+                size,
+                crate::MIN_CELLS,
+                PointDistribution::Jittered,
+                topology,
+                &mut rng,
+                &CancelToken::new(),
+            ).unwrap();
+
+            let polygons: Vec<_> = grid.voronoi.get_clipped_cell_vertex_coords().collect();
+            let area: f32 = polygons.iter().map(|polygon| geometry::area_centroid(polygon).0).sum();
+            assert!((area - 60_000.0).abs() < 1.0, "{} cells cover {}", topology, area);
+            for y in 0..40 {
+                for x in 0..60 {
+                    let p = Point::new(x as f32 * 5.0 + 2.37, y as f32 * 5.0 + 1.61);
+                    let covering = polygons.iter().filter(|polygon| geometry::contains(polygon, p)).count();
+                    assert_eq!(covering, 1, "{:?} of a {} map", p, topology);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn cells_and_vertices_agree() {
+        for &topology in &[Topology::Flat, Topology::Cylinder, Topology::Sphere] {
+            let mut rng = StdRng::seed_from_u64(8);
+            let grid = Grid::new(
+                Size::new(300, 200),
+                crate::MIN_CELLS,
+                PointDistribution::Jittered,
+                topology,
+                &mut rng,
+                &CancelToken::new(),
+            ).unwrap();
+            let voronoi = &grid.voronoi;
+            assert_eq!(voronoi.cell_count(), grid.points.len(), "{}", topology);
+
+            let mut area = 0.0;
+            for c in voronoi.cells() {
+                let cell = voronoi.cell(c);
+                assert_eq!(cell.edge_lengths.len(), cell.adjacent_cells.len(), "{:?} of a {} map", c, topology);
+                for &n in cell.adjacent_cells {
+                    assert_ne!(n, c);
+                    if !voronoi.is_border_point(n) {
+                        assert!(voronoi.cell(n).adjacent_cells.contains(&c), "{:?} and {:?} of a {} map", c, n, topology);
+                    }
+                }
+                for &v in cell.vertices {
+                    assert!(voronoi.vertex(v).connected_cells.contains(&c), "{:?} and {:?} of a {} map", c, v, topology);
+                }
+                // The perimeter also runs along the map edges.
+                let edges: f32 = cell.edge_lengths.iter().sum();
+                assert!(edges <= cell.perimeter + 1e-2, "{:?} of a {} map", c, topology);
+                let inside = voronoi.get_cell_vertices(c).unwrap().all(|v| {
+                    v.coords.x > 1.0 && v.coords.x < 299.0 && v.coords.y > 1.0 && v.coords.y < 199.0
+                });
+                if inside && topology == Topology::Flat {
+                    assert!((edges - cell.perimeter).abs() < 1e-2, "{:?} of a {} map", c, topology);
+                }
+                area += cell.area;
+            }
+            assert!((area - 60_000.0).abs() < 1.0, "{} cells cover {}", topology, area);
+
+            for v in 0..voronoi.vertex_count() {
+                let v = VertexIndex::from(v);
+                for &w in voronoi.vertex(v).connected_vertices.iter().flatten() {
+                    assert!(voronoi.vertex(w).connected_vertices.contains(&Some(v)), "{:?} and {:?} of a {} map", v, w, topology);
+                }
+            }
+            assert!(voronoi.get_cell_vertices(voronoi.cell_count().into()).is_none());
+        }
+    }
+}